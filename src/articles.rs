@@ -1,19 +1,27 @@
-use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use chrono::prelude::*;
+use chrono_tz::Tz;
 use handlebars::RenderError;
 use regex::Regex;
 use rocket::form::FromForm;
+use rocket::http::RawStr;
 use rusty_ulid::Ulid;
 use serde_derive::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
 
-use crate::index::{self, Index};
+#[cfg(test)]
+use crate::clock::{Clock, SystemClock};
+use crate::format::{Format, FORMAT_PROPERTY};
+use crate::front_matter::{self, FrontMatter};
+use crate::index::{self, Entry, Index};
+use crate::layouts::LAYOUT_PROPERTY;
 use crate::query::Query;
+use crate::validation::ValidationErrors;
 
 pub type PropertySet = HashMap<String, String>;
 
@@ -24,42 +32,209 @@ pub struct Article {
     pub title: String,
     #[serde(skip_serializing, skip_deserializing)]
     pub body: String,
+    // BLAKE3 hash of the body, bodies are stored by their content
+    #[serde(default)]
+    pub hash: String,
     pub properties: PropertySet,
     pub tags: HashSet<String>,
+    // Other ids that lead to this article, such as ids it had before
+    #[serde(default)]
+    pub aliases: HashSet<String>,
+    #[serde(default)]
+    pub status: Status,
+    // When the article is or was published, scheduled articles go live then.
+    // Articles are dated by it when it is given.
+    #[serde(default)]
+    pub publish_at: Option<DateTime<Utc>>,
+    // Ids of the articles this one links to, by kind of link
+    #[serde(default)]
+    pub links: Links,
+}
+
+pub type Links = BTreeMap<LinkKind, BTreeSet<String>>;
+
+// Typed links between articles, such as the parent of a page in a docs tree.
+// An article has at most one parent, next and prev, but any number of related
+// articles. Links are indexed both ways, so an article can list the articles
+// linking to it.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    Parent,
+    Related,
+    Next,
+    Prev,
+}
+
+impl LinkKind {
+    pub const ALL: [LinkKind; 4] = [
+        LinkKind::Parent,
+        LinkKind::Related,
+        LinkKind::Next,
+        LinkKind::Prev,
+    ];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkKind::Parent => "parent",
+            LinkKind::Related => "related",
+            LinkKind::Next => "next",
+            LinkKind::Prev => "prev",
+        }
+    }
+
+    pub fn is_single(&self) -> bool {
+        *self != LinkKind::Related
+    }
+}
+
+// The publishing lifecycle of an article, only published articles and
+// scheduled ones whose time has come are shown to the public
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Draft,
+    Scheduled,
+    #[default]
+    Published,
+    Archived,
+}
+
+impl Status {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "draft" => Some(Status::Draft),
+            "scheduled" => Some(Status::Scheduled),
+            "published" => Some(Status::Published),
+            "archived" => Some(Status::Archived),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Draft => "draft",
+            Status::Scheduled => "scheduled",
+            Status::Published => "published",
+            Status::Archived => "archived",
+        }
+    }
+}
+
+// Parses the times accepted for publish_at, either RFC 3339 or the format of
+// datetime-local inputs, which are taken to be UTC
+pub fn parse_datetime(text: &str) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time.with_timezone(&Utc));
+    }
+    ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .map(|time| Utc.from_utc_datetime(&time))
+}
+
+// Parses a timestamp property, as given by hand or as written by
+// Article::date
+fn parse_timestamp(text: &str) -> Option<DateTime<Utc>> {
+    text.trim()
+        .parse::<DateTime<Utc>>()
+        .ok()
+        .or_else(|| parse_datetime(text))
 }
 
 impl Article {
-    // TODO: NewArticleRequest is untrusted so should be validated / sanitized
+    // The request should have been validated first, anything malformed that
+    // remains is left out. Front matter in the body takes precedence over the
+    // other fields and is not stored as part of the body.
+    #[cfg(test)]
     pub fn new(request: &NewArticleRequest) -> Article {
+        Self::new_at(request, SystemClock.now(), Tz::UTC)
+    }
+
+    // Creates an article at the time now, which dates it unless the request
+    // gives a date of its own. Calendar properties are in timezone.
+    pub fn new_at(request: &NewArticleRequest, now: DateTime<Utc>, timezone: Tz) -> Article {
+        let (front, body) = front_matter::split(&request.body).unwrap_or((None, &request.body));
+        let front = front.unwrap_or_default();
+        let id = front.id.as_deref().unwrap_or(&request.id);
         let mut article = Article {
-            key: Ulid::generate(),
-            // TODO: ID should be optionally specified by user, or generated if not as a slug
-            id: request.id.clone(), //slug_from_title(&request.title).to_string(),
-            title: request.title.clone(),
-            body: request.body.clone(),
+            key: request.key.parse().unwrap_or_else(|_| Ulid::generate()),
+            // Without an id, the index generates one from the title
+            id: normalize_id(id.trim()),
+            title: front.title.clone().unwrap_or_else(|| request.title.clone()),
+            body: body.to_string(),
+            hash: content_hash(body.as_bytes()),
             properties: PropertySet::new(),
             tags: HashSet::new(),
+            aliases: HashSet::new(),
+            status: Status::parse(front.status.as_deref().unwrap_or(&request.status))
+                .unwrap_or_default(),
+            publish_at: parse_datetime(front.publish_at.as_deref().unwrap_or(&request.publish_at)),
+            links: Links::new(),
         };
         for property in request.properties.split_whitespace() {
-            let (key, value) = property.split_once(':').unwrap();
-            article.properties.insert(key.into(), value.into());
+            if let Some((key, value)) = property.split_once(':') {
+                article.properties.insert(key.into(), value.into());
+            }
+        }
+        for (kind, target) in request_links(&request.links, &front) {
+            if let Some(kind) = LinkKind::parse(kind) {
+                article
+                    .links
+                    .entry(kind)
+                    .or_default()
+                    .insert(normalize_id(target));
+            }
         }
-        Self::add_default_properties(&mut article.properties);
-        for tag in request.tags.split_whitespace() {
-            article.tags.insert(tag.into());
+        article.properties.extend(front.properties);
+        article.date(now, timezone);
+        let tags = request.tags.split_whitespace();
+        for tag in tags.chain(front.tags.iter().map(String::as_str)) {
+            article.tags.insert(tag.nfc().collect());
+        }
+        let aliases = request.aliases.split_whitespace();
+        for alias in aliases.chain(front.aliases.iter().map(String::as_str)) {
+            article.aliases.insert(normalize_id(alias));
         }
         article
     }
 
-    fn add_default_properties(properties: &mut PropertySet) {
-        let now: DateTime<Utc> = Utc::now();
-        properties.insert("timestamp".to_string(), now.to_string());
-        properties.insert("epoch".to_string(), now.timestamp().to_string());
-        properties.insert("year".to_string(), now.format("%Y").to_string());
-        properties.insert("month".to_string(), now.format("%m").to_string());
-        properties.insert("day".to_string(), now.format("%d").to_string());
+    // Fills in the default properties from the date of the article: its
+    // publish_at, else a timestamp or epoch property it was given, else now.
+    // Given year, month and day properties are kept as they are.
+    fn date(&mut self, now: DateTime<Utc>, timezone: Tz) {
+        let properties = &mut self.properties;
+        let date = self
+            .publish_at
+            .or_else(|| properties.get("timestamp").and_then(|t| parse_timestamp(t)))
+            .or_else(|| {
+                properties
+                    .get("epoch")
+                    .and_then(|epoch| epoch.parse().ok())
+                    .and_then(|epoch| Utc.timestamp_opt(epoch, 0).single())
+            })
+            .unwrap_or(now);
+        properties.insert("timestamp".to_string(), date.to_string());
+        properties.insert("epoch".to_string(), date.timestamp().to_string());
+        let local = date.with_timezone(&timezone);
+        for (name, format) in [("year", "%Y"), ("month", "%m"), ("day", "%d")] {
+            properties
+                .entry(name.to_string())
+                .or_insert_with(|| local.format(format).to_string());
+        }
     }
 
+    #[cfg(test)]
     pub fn epoch(&self) -> i64 {
         self.properties["epoch"].parse().unwrap()
     }
@@ -67,36 +242,410 @@ impl Article {
     pub fn timestamp(&self) -> String {
         self.properties["timestamp"].clone()
     }
+
+    // The path the article is served at, with each part of its id encoded
+    pub fn uri(&self) -> String {
+        self.id
+            .split('/')
+            .map(|segment| format!("/{}", RawStr::new(segment).percent_encode()))
+            .collect()
+    }
+
+    // The ids this article links to with links of kind
+    pub fn links(&self, kind: LinkKind) -> impl Iterator<Item = &String> {
+        self.links.get(&kind).into_iter().flatten()
+    }
+
+    // Whether the public may see the article at the time now
+    pub fn is_public(&self, now: DateTime<Utc>) -> bool {
+        match self.status {
+            Status::Published => true,
+            Status::Scheduled => self.publish_at.is_some_and(|at| at <= now),
+            Status::Draft | Status::Archived => false,
+        }
+    }
+}
+
+// Ids are kept in Unicode normalization form C, so the same text typed on
+// different systems always names the same article
+pub fn normalize_id(id: &str) -> String {
+    id.nfc().collect()
+}
+
+// Ids are hierarchical, segments separated by / map straight onto the path an
+// article is served at, so blog/2023/hello is served at /blog/2023/hello.
+// Segments can't be empty or hidden, so an id never escapes the directories
+// it is looked up in.
+pub fn validate_id(id: &str) -> Result<(), &'static str> {
+    if id.is_empty() {
+        return Ok(());
+    }
+    if id.chars().any(|c| c == '\\' || c.is_control()) {
+        return Err("must not contain backslashes or control characters");
+    }
+    for segment in id.split('/') {
+        if segment.is_empty() {
+            return Err("must not start or end with /, or contain //");
+        } else if segment.starts_with('.') {
+            return Err("must not have parts starting with .");
+        } else if segment == "*" || segment == "**" {
+            return Err("must not have * as a part");
+        }
+    }
+    Ok(())
+}
+
+pub fn content_hash(body: &[u8]) -> String {
+    blake3::hash(body).to_hex().to_string()
 }
 
-fn slug_from_title(title: &str) -> Cow<str> {
-    let scrubbing_regex = Regex::new(r"[^A-Za-z0-9]+").unwrap();
-    scrubbing_regex.replace_all(title, "_")
+// Longest slug generated from a title, longer titles are cut at a word
+const MAX_SLUG_LENGTH: usize = 64;
+
+// Turns a title into an id, transliterated to ASCII, lowercase and hyphenated
+pub fn slug_from_title(title: &str) -> String {
+    let scrubbing_regex = Regex::new(r"[^a-z0-9]+").unwrap();
+    let ascii = deunicode::deunicode(title).to_lowercase();
+    let slug = scrubbing_regex.replace_all(&ascii, "-");
+    let mut slug = slug.trim_matches('-');
+    if slug.len() > MAX_SLUG_LENGTH {
+        slug = &slug[..MAX_SLUG_LENGTH];
+        if let Some((words, _)) = slug.rsplit_once('-') {
+            slug = words;
+        }
+    }
+    slug.to_string()
 }
 
 #[derive(Serialize, Default, Deserialize, FromForm, Debug)]
 pub struct NewArticleRequest {
+    // The key of the article to replace, a new article is created without
+    #[serde(default)]
+    #[field(default = String::new())]
+    pub key: String,
     pub id: String,
     pub title: String,
     pub body: String,
     pub properties: String,
     pub tags: String,
+    #[serde(default)]
+    #[field(default = String::new())]
+    pub aliases: String,
+    // draft, scheduled, published (the default) or archived
+    #[serde(default)]
+    #[field(default = String::new())]
+    pub status: String,
+    #[serde(default)]
+    #[field(default = String::new())]
+    pub publish_at: String,
+    // Written as kind:id, such as parent:docs related:faq
+    #[serde(default)]
+    #[field(default = String::new())]
+    pub links: String,
 }
 
+// The links of a request as (kind, id) pairs, parent, next and prev given in
+// front matter replace those of the form
+fn request_links<'a>(links: &'a str, front: &'a FrontMatter) -> Vec<(&'a str, &'a str)> {
+    let in_front = |kind: &str| {
+        LinkKind::parse(kind)
+            .is_some_and(|kind| kind.is_single() && front.links.iter().any(|(k, _)| *k == kind))
+    };
+    links
+        .split_whitespace()
+        .map(|link| link.split_once(':').unwrap_or((link, "")))
+        .filter(|(kind, _)| !in_front(kind))
+        .chain(
+            front
+                .links
+                .iter()
+                .map(|(kind, target)| (kind.name(), target.as_str())),
+        )
+        .collect()
+}
+
+// Limits on what a NewArticleRequest may hold, lengths are in characters
+// except for the body, which is in bytes
+pub const MAX_ID_LENGTH: usize = 128;
+pub const MAX_TITLE_LENGTH: usize = 256;
+pub const MAX_TAG_LENGTH: usize = 64;
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Properties set on every article, derived from its date unless given
+pub const DEFAULT_PROPERTIES: &[&str] = &["timestamp", "epoch", "year", "month", "day"];
+
+impl NewArticleRequest {
+    // The form for editing an existing article, everything but its id and
+    // date is written as front matter of the body
+    pub fn for_editing(article: &Article, body: &str) -> Self {
+        let timestamp = parse_timestamp(&article.timestamp()).map(|t| t.to_rfc3339());
+        NewArticleRequest {
+            key: article.key.to_string(),
+            id: article.id.clone(),
+            properties: timestamp
+                .map(|t| format!("timestamp:{}", t))
+                .unwrap_or_default(),
+            body: front_matter::render(article, body),
+            ..Default::default()
+        }
+    }
+
+    // Checks an untrusted request, problems are reported per form field
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+
+        let front = match front_matter::split(&self.body) {
+            Ok((front, _)) => front.unwrap_or_default(),
+            Err(message) => {
+                errors.add("body", message);
+                FrontMatter::default()
+            }
+        };
+        let id = front.id.as_deref().unwrap_or(&self.id).trim();
+        let title = front.title.as_deref().unwrap_or(&self.title);
+
+        if !self.key.is_empty() && self.key.parse::<Ulid>().is_err() {
+            errors.add("key", "is not a valid key");
+        }
+        if id.is_empty() && title.trim().is_empty() {
+            errors.add("title", "is required when no id is given");
+        } else if id.is_empty() && slug_from_title(title).is_empty() {
+            errors.add(
+                "title",
+                "must contain letters or digits when no id is given",
+            );
+        }
+        if id.chars().count() > MAX_ID_LENGTH {
+            errors.add(
+                "id",
+                format!("must be at most {} characters", MAX_ID_LENGTH),
+            );
+        }
+        if !id.chars().all(is_id_char) {
+            errors.add("id", "may only contain letters, digits, -, _, . and /");
+        } else if let Err(message) = validate_id(id) {
+            errors.add("id", message);
+        }
+        let aliases = self.aliases.split_whitespace();
+        for alias in aliases.chain(front.aliases.iter().map(String::as_str)) {
+            if !alias.chars().all(is_id_char) || validate_id(alias).is_err() {
+                errors.add("aliases", format!("{} is not a valid id", alias));
+            }
+        }
+
+        let mut single = HashSet::new();
+        for (kind, target) in request_links(&self.links, &front) {
+            match LinkKind::parse(kind) {
+                None => errors.add(
+                    "links",
+                    format!("{} must be parent, related, next or prev", kind),
+                ),
+                Some(_) if target.is_empty() => {
+                    errors.add("links", format!("{} is missing an id", kind))
+                }
+                Some(_) if !target.chars().all(is_id_char) || validate_id(target).is_err() => {
+                    errors.add("links", format!("{} is not a valid id", target))
+                }
+                Some(_) if !id.is_empty() && normalize_id(target) == normalize_id(id) => {
+                    errors.add("links", format!("{} can't link to itself", kind))
+                }
+                Some(kind) if kind.is_single() && !single.insert(kind) => {
+                    errors.add("links", format!("only one {} may be given", kind.name()))
+                }
+                Some(_) => {}
+            }
+        }
+
+        if title.chars().count() > MAX_TITLE_LENGTH {
+            errors.add(
+                "title",
+                format!("must be at most {} characters", MAX_TITLE_LENGTH),
+            );
+        }
+        if self.body.len() > MAX_BODY_SIZE {
+            errors.add("body", format!("must be at most {} bytes", MAX_BODY_SIZE));
+        }
+
+        let mut properties = vec![];
+        for property in self.properties.split_whitespace() {
+            match property.split_once(':') {
+                None => errors.add(
+                    "properties",
+                    format!("{} must be written as name:value", property),
+                ),
+                Some((name, "")) => {
+                    errors.add("properties", format!("{} is missing a value", name))
+                }
+                Some(property) => properties.push(property),
+            }
+        }
+        let property_name = Regex::new(r"^[A-Za-z0-9_.-]+$").unwrap();
+        for (name, value) in properties.into_iter().chain(
+            front
+                .properties
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        ) {
+            if !property_name.is_match(name) {
+                errors.add(
+                    "properties",
+                    format!("{} may only contain letters, digits, -, _ and .", name),
+                );
+            } else if let Some(message) = check_default_property(name, value) {
+                errors.add("properties", format!("{} {}", name, message));
+            } else if front_matter::RESERVED_KEYS.contains(&name) {
+                errors.add("properties", format!("{} is not a property", name));
+            }
+        }
+        let status = front.status.as_deref().unwrap_or(&self.status).trim();
+        let publish_at = front
+            .publish_at
+            .as_deref()
+            .unwrap_or(&self.publish_at)
+            .trim();
+        match Status::parse(status) {
+            None if !status.is_empty() => {
+                errors.add("status", "must be draft, scheduled, published or archived")
+            }
+            Some(Status::Scheduled) if publish_at.is_empty() => {
+                errors.add("publish_at", "is required for scheduled articles")
+            }
+            _ => {}
+        }
+        if !publish_at.is_empty() && parse_datetime(publish_at).is_none() {
+            errors.add(
+                "publish_at",
+                "must be a date and time such as 2023-01-31T09:00",
+            );
+        }
+
+        let format = self
+            .properties
+            .split_whitespace()
+            .filter_map(|property| property.split_once(':'))
+            .chain(
+                front
+                    .properties
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str())),
+            )
+            .rfind(|(name, _)| *name == FORMAT_PROPERTY);
+        if let Some((_, format)) = format {
+            if Format::parse(format).is_none() {
+                errors.add(
+                    "properties",
+                    format!("{} must be html, markdown or text", FORMAT_PROPERTY),
+                );
+            }
+        }
+
+        let tags = self.tags.split_whitespace();
+        for tag in tags.chain(front.tags.iter().map(String::as_str)) {
+            if tag.chars().count() > MAX_TAG_LENGTH {
+                errors.add(
+                    "tags",
+                    format!("{} must be at most {} characters", tag, MAX_TAG_LENGTH),
+                );
+            } else if !tag
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+            {
+                // Anything else could be mistaken for other parts of a query
+                errors.add(
+                    "tags",
+                    format!("{} may only contain letters, digits, - and _", tag),
+                );
+            }
+        }
+
+        errors.into_result()
+    }
+}
+
+// Default properties may be given to date an article, so long as they read as
+// a date
+fn check_default_property(name: &str, value: &str) -> Option<&'static str> {
+    let number = |range: std::ops::RangeInclusive<u32>| {
+        value.parse::<u32>().ok().filter(|n| range.contains(n))
+    };
+    match name {
+        "timestamp" if parse_timestamp(value).is_none() => Some("must be a date and time"),
+        "epoch" if value.parse::<i64>().is_err() => Some("must be a number of seconds"),
+        "year" if number(0..=9999).is_none() => Some("must be a year"),
+        "month" if number(1..=12).is_none() => Some("must be a month from 1 to 12"),
+        "day" if number(1..=31).is_none() => Some("must be a day from 1 to 31"),
+        LAYOUT_PROPERTY if value.is_empty() || validate_id(value).is_err() => {
+            Some("must be the id of an article")
+        }
+        _ => None,
+    }
+}
+
+fn is_id_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '/')
+}
+
+// Which articles may be seen: every one for administrators previewing drafts,
+// otherwise those public at a time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    Drafts,
+    PublicAt(DateTime<Utc>),
+}
+
+impl Visibility {
+    pub fn shows(&self, article: &Article) -> bool {
+        match self {
+            Visibility::Drafts => true,
+            Visibility::PublicAt(now) => article.is_public(*now),
+        }
+    }
+}
+
+// Finds the first article matching query that may be seen
+pub fn first_visible(
+    index: &mut dyn Index,
+    query: &Query,
+    visibility: Visibility,
+) -> Result<Box<dyn Entry>> {
+    index
+        .search(query)?
+        .find(|entry| visibility.shows(&entry.article()))
+        .ok_or_else(|| index::Error::ArticleNotFound.into())
+}
+
+// Finds the body of an article, along with the article itself when it came
+// from the index
 pub fn lookup_article(
     index: &mut Box<dyn Index>,
+    templates: &Path,
     query_str: &str,
-) -> Result<Box<dyn std::io::Read>> {
+    visibility: Visibility,
+) -> Result<(Option<Article>, Box<dyn std::io::Read>)> {
     println!("lookup_article(query_str: {:?})", query_str);
     let query: Query = query_str.try_into()?;
 
-    match index.first(&query) {
-        Ok(r) => r.body(),
+    // A query for nothing but a full hash can be read straight from the
+    // content store without scanning the index, though only when it doesn't
+    // matter which article the content belongs to
+    if let Some(ref hash) = query.hash {
+        let pinned_only = visibility == Visibility::Drafts
+            && query.id.is_none()
+            && query.parent.is_none()
+            && query.tags.is_empty()
+            && query.properties.is_empty();
+        if pinned_only && hash.len() == blake3::OUT_LEN * 2 {
+            return Ok((None, index.content(hash)?));
+        }
+    }
+
+    match first_visible(&mut **index, &query, visibility) {
+        Ok(r) => Ok((Some(r.article()), r.body()?)),
         Err(e) => match e.downcast_ref::<index::Error>() {
             Some(index::Error::ArticleNotFound) => {
                 println!("failed to find article, trying fallback...");
-                File::open(load_fallback(&query)?)
-                    .map(|f| Box::new(f) as Box<dyn std::io::Read>)
+                File::open(load_fallback(templates, &query)?)
+                    .map(|f| (None, Box::new(f) as Box<dyn std::io::Read>))
                     .map_err(|_| RenderError::new("error finding fallback article").into())
             }
             _ => {
@@ -107,10 +656,13 @@ pub fn lookup_article(
     }
 }
 
-fn load_fallback(query: &Query) -> Result<PathBuf> {
+fn load_fallback(templates: &Path, query: &Query) -> Result<PathBuf> {
     if let Some(ref id) = query.id {
         println!("query#id = {:?}", id);
-        return Ok(Path::new("templates/").join(format!("{}.html.hbs", id)));
+        if validate_id(id).is_err() {
+            return Err(index::Error::ArticleNotFound.into());
+        }
+        return Ok(templates.join(format!("{}.html.hbs", id)));
     }
     Err(index::Error::ArticleNotFound.into())
 }
@@ -144,11 +696,303 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_validate_request() {
+        let valid = NewArticleRequest {
+            id: "blog/2023/hello-world".to_string(),
+            title: "Hello".to_string(),
+            properties: "price:9.99 colour:red".to_string(),
+            tags: "news café".to_string(),
+            aliases: "hello".to_string(),
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+
+        let errors = NewArticleRequest {
+            key: "not-a-key".to_string(),
+            id: "../etc/passwd".to_string(),
+            title: "x".repeat(MAX_TITLE_LENGTH + 1),
+            body: "x".repeat(MAX_BODY_SIZE + 1),
+            properties: "price no-colon: year:soon layout:../shell".to_string(),
+            tags: "@main price>1 ok".to_string(),
+            aliases: "with space../".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            errors.fields.keys().collect::<Vec<_>>(),
+            [
+                "aliases",
+                "body",
+                "id",
+                "key",
+                "properties",
+                "tags",
+                "title"
+            ]
+        );
+        assert_eq!(
+            errors.fields["properties"],
+            [
+                "price must be written as name:value",
+                "no-colon is missing a value",
+                "year must be a year",
+                "layout must be the id of an article"
+            ]
+        );
+        assert_eq!(errors.fields["tags"].len(), 2);
+
+        let errors = NewArticleRequest {
+            id: "page".to_string(),
+            properties: "format:rtf".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            errors.fields["properties"],
+            ["format must be html, markdown or text"]
+        );
+
+        let empty: NewArticleRequest = Default::default();
+        let errors = empty.validate().unwrap_err();
+        assert_eq!(errors.fields["title"], ["is required when no id is given"]);
+        let punctuation = NewArticleRequest {
+            title: "!!!".to_string(),
+            ..Default::default()
+        };
+        let errors = punctuation.validate().unwrap_err();
+        assert_eq!(
+            errors.fields["title"],
+            ["must contain letters or digits when no id is given"]
+        );
+        let errors = NewArticleRequest {
+            id: "hello world".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(errors.fields.contains_key("id"));
+
+        // Malformed properties no longer take the server down
+        let article = Article::new(&NewArticleRequest {
+            properties: "price".to_string(),
+            ..Default::default()
+        });
+        assert!(!article.properties.contains_key("price"));
+    }
+
+    #[test]
+    fn test_dates() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 2, 30, 0).unwrap();
+        let article = Article::new_at(&Default::default(), now, Tz::UTC);
+        assert_eq!(article.timestamp(), now.to_string());
+        assert_eq!(article.epoch(), now.timestamp());
+        assert_eq!(article.properties["year"], "2024");
+        assert_eq!(article.properties["day"], "01");
+
+        // Calendar properties are in the time zone of the site
+        let article = Article::new_at(&Default::default(), now, Tz::America__New_York);
+        assert_eq!(article.properties["year"], "2023");
+        assert_eq!(article.properties["month"], "12");
+        assert_eq!(article.properties["day"], "31");
+
+        // Old posts keep the date they were given
+        let imported = |properties: &str, publish_at: &str| {
+            let request = NewArticleRequest {
+                id: "old".to_string(),
+                properties: properties.to_string(),
+                publish_at: publish_at.to_string(),
+                ..Default::default()
+            };
+            assert!(request.validate().is_ok());
+            Article::new_at(&request, now, Tz::UTC)
+        };
+        let article = imported("", "2009-05-06T07:08");
+        assert_eq!(article.timestamp(), "2009-05-06 07:08:00 UTC");
+        assert_eq!(article.properties["year"], "2009");
+        let article = imported("timestamp:2009-05-06", "");
+        assert_eq!(article.timestamp(), "2009-05-06 00:00:00 UTC");
+        assert_eq!(article.epoch(), 1241568000);
+        let article = imported("epoch:1241568000 year:2008", "");
+        assert_eq!(article.timestamp(), "2009-05-06 00:00:00 UTC");
+        assert_eq!(article.properties["year"], "2008");
+        assert_eq!(article.properties["month"], "05");
+
+        // Editing an article keeps its date
+        let request = NewArticleRequest::for_editing(&article, "");
+        let edited = Article::new_at(&request, now, Tz::UTC);
+        assert_eq!(edited.timestamp(), article.timestamp());
+
+        let errors = NewArticleRequest {
+            id: "old".to_string(),
+            properties: "timestamp:yesterday epoch:1.5 month:13".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(errors.fields["properties"].len(), 3);
+    }
+
+    #[test]
+    fn test_front_matter() {
+        let request = NewArticleRequest {
+            title: "Form title".to_string(),
+            tags: "form".to_string(),
+            properties: "colour:red".to_string(),
+            body: "---\ntitle: Hello\nid: hello\ntags: [news]\nprice: 9.99\n---\n<p>Hi</p>"
+                .to_string(),
+            ..Default::default()
+        };
+        assert!(request.validate().is_ok());
+        let article = Article::new(&request);
+        assert_eq!(article.title, "Hello");
+        assert_eq!(article.id, "hello");
+        assert_eq!(article.body, "<p>Hi</p>");
+        assert_eq!(article.hash, content_hash(b"<p>Hi</p>"));
+        assert!(article.tags.contains("news") && article.tags.contains("form"));
+        assert_eq!(article.properties["price"], "9.99");
+        assert_eq!(article.properties["colour"], "red");
+
+        let errors = NewArticleRequest {
+            body: "---\nid: ../up\ntags: [a b]\ntimestamp: now\n---\n".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            errors.fields.keys().collect::<Vec<_>>(),
+            ["id", "properties", "tags"]
+        );
+        let errors = NewArticleRequest {
+            id: "page".to_string(),
+            body: "---\ntitle: [\n---\n".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(errors.fields.contains_key("body"));
+    }
+
+    #[test]
+    fn test_status() {
+        let now = Utc::now();
+        let mut article = Article::new(&Default::default());
+        assert_eq!(article.status, Status::Published);
+        assert!(article.is_public(now));
+        article.status = Status::Draft;
+        assert!(!article.is_public(now));
+        article.status = Status::Archived;
+        assert!(!article.is_public(now));
+
+        // Scheduled articles go live once their time has come
+        let article = Article::new(&NewArticleRequest {
+            body: "---\nstatus: scheduled\npublish_at: 2030-01-02\n---\nsoon".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(article.status, Status::Scheduled);
+        let at = parse_datetime("2030-01-02T00:00:00Z").unwrap();
+        assert_eq!(article.publish_at, Some(at));
+        assert!(!article.is_public(at - chrono::Duration::seconds(1)));
+        assert!(article.is_public(at));
+
+        let errors = NewArticleRequest {
+            id: "page".to_string(),
+            status: "hidden".to_string(),
+            publish_at: "soon".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            errors.fields.keys().collect::<Vec<_>>(),
+            ["publish_at", "status"]
+        );
+        let errors = NewArticleRequest {
+            id: "page".to_string(),
+            status: "scheduled".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(errors.fields.contains_key("publish_at"));
+    }
+
+    #[test]
+    fn test_links() {
+        let request = NewArticleRequest {
+            id: "install".to_string(),
+            links: "parent:guide related:faq".to_string(),
+            body: "---\nparent: docs\nrelated: [setup]\n---\nbody".to_string(),
+            ..Default::default()
+        };
+        assert!(request.validate().is_ok());
+        let article = Article::new(&request);
+        assert_eq!(
+            article.links(LinkKind::Parent).collect::<Vec<_>>(),
+            ["docs"]
+        );
+        assert_eq!(
+            article.links(LinkKind::Related).collect::<Vec<_>>(),
+            ["faq", "setup"]
+        );
+        assert_eq!(article.links(LinkKind::Next).count(), 0);
+
+        let errors = NewArticleRequest {
+            id: "install".to_string(),
+            links: "parent:a parent:b sibling:c next: prev:../x related:install".to_string(),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            errors.fields["links"],
+            [
+                "only one parent may be given",
+                "sibling must be parent, related, next or prev",
+                "next is missing an id",
+                "../x is not a valid id",
+                "related can't link to itself"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_id() {
+        assert!(validate_id("").is_ok());
+        assert!(validate_id("main").is_ok());
+        assert!(validate_id("blog/2023/hello").is_ok());
+        assert!(validate_id("v1.2").is_ok());
+        assert!(validate_id("../secret").is_err());
+        assert!(validate_id("blog/../../secret").is_err());
+        assert!(validate_id("blog/.hidden").is_err());
+        assert!(validate_id("/blog").is_err());
+        assert!(validate_id("blog/").is_err());
+        assert!(validate_id("blog//hello").is_err());
+        assert!(validate_id("blog/*").is_err());
+        assert!(validate_id("blog\\hello").is_err());
+    }
+
     #[test]
     fn test_slug() {
         assert_eq!(slug_from_title("abcd"), "abcd");
-        assert_eq!(slug_from_title("a!@#bcd"), "a_bcd");
-        assert_eq!(slug_from_title("number 10"), "number_10");
+        assert_eq!(slug_from_title("a!@#bcd"), "a-bcd");
+        assert_eq!(slug_from_title("number 10"), "number-10");
         assert_eq!(slug_from_title(""), "");
+        assert_eq!(slug_from_title("  Hello, World! "), "hello-world");
+        assert_eq!(slug_from_title("Crème Brûlée"), "creme-brulee");
+        assert_eq!(slug_from_title("東京"), "dong-jing");
+        assert_eq!(slug_from_title("!!!"), "");
+        let long = slug_from_title(&"word ".repeat(20));
+        assert!(long.len() <= MAX_SLUG_LENGTH);
+        assert!(long.ends_with("word"));
+    }
+
+    #[test]
+    fn test_uri() {
+        let mut article = Article::new(&Default::default());
+        article.id = "blog/café #1?".to_string();
+        assert_eq!(article.uri(), "/blog/caf%C3%A9%20%231%3F");
     }
 }
@@ -0,0 +1,165 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rocket::catch;
+use rocket::http::{Header, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use serde_derive::Deserialize;
+
+use crate::site::Site;
+
+// Credentials of the administrator of a site, checked with HTTP basic auth:
+//
+//   [default.admin]
+//   username = "admin"
+//   password = "secret"
+#[derive(Clone, Debug, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    // Checks the value of an Authorization header
+    pub fn check(&self, authorization: Option<&str>) -> bool {
+        let encoded = match authorization.and_then(|value| value.strip_prefix("Basic ")) {
+            Some(encoded) => encoded.trim(),
+            None => return false,
+        };
+        let decoded = match STANDARD.decode(encoded).map(String::from_utf8) {
+            Ok(Ok(decoded)) => decoded,
+            _ => return false,
+        };
+        match decoded.split_once(':') {
+            Some((username, password)) => {
+                constant_time_eq(username.as_bytes(), self.username.as_bytes())
+                    & constant_time_eq(password.as_bytes(), self.password.as_bytes())
+            }
+            None => false,
+        }
+    }
+}
+
+// Compares without returning early, so timing doesn't reveal how much of a
+// guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn is_admin(request: &Request<'_>) -> Option<bool> {
+    let site = match request.guard::<Site>().await {
+        Outcome::Success(site) => site,
+        _ => return None,
+    };
+    Some(match site.admin {
+        Some(ref credentials) => credentials.check(request.headers().get_one("Authorization")),
+        None => true,
+    })
+}
+
+// Admin is a request guard for routes only administrators may use. Sites
+// without credentials configured are open to everyone.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match is_admin(request).await {
+            Some(true) => Outcome::Success(Admin),
+            Some(false) => Outcome::Error((Status::Unauthorized, ())),
+            None => Outcome::Error((Status::NotFound, ())),
+        }
+    }
+}
+
+// Preview is true when an administrator asks to see unpublished articles by
+// adding ?preview to the URL of a public page. Sites without credentials
+// configured have no administrator to preview, so they never show them.
+pub struct Preview(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Preview {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let requested = request.uri().query().is_some_and(|query| {
+            query
+                .segments()
+                .any(|(k, v)| k == "preview" && v != "false")
+        });
+        if !requested {
+            return Outcome::Success(Preview(false));
+        }
+        let site = match request.guard::<Site>().await {
+            Outcome::Success(site) => site,
+            _ => return Outcome::Error((Status::NotFound, ())),
+        };
+        let authorization = request.headers().get_one("Authorization");
+        match may_preview(site.admin.as_ref(), authorization) {
+            Some(true) => Outcome::Success(Preview(true)),
+            Some(false) => Outcome::Error((Status::Unauthorized, ())),
+            None => Outcome::Success(Preview(false)),
+        }
+    }
+}
+
+// Whether a request with authorization may preview, or None when there are no
+// credentials to check it against
+fn may_preview(admin: Option<&Credentials>, authorization: Option<&str>) -> Option<bool> {
+    admin.map(|credentials| credentials.check(authorization))
+}
+
+// Asks the browser for credentials
+pub struct Challenge;
+
+impl<'r> Responder<'r, 'static> for Challenge {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        Response::build_from("Unauthorized".respond_to(request)?)
+            .status(Status::Unauthorized)
+            .header(Header::new("WWW-Authenticate", r#"Basic realm="ota""#))
+            .ok()
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized() -> Challenge {
+    Challenge
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::*;
+
+    #[test]
+    fn test_check() {
+        let credentials = Credentials {
+            username: "admin".to_string(),
+            password: "pa:ss".to_string(),
+        };
+        let header = format!("Basic {}", STANDARD.encode("admin:pa:ss"));
+        assert!(credentials.check(Some(&header)));
+
+        let wrong = format!("Basic {}", STANDARD.encode("admin:pass"));
+        assert!(!credentials.check(Some(&wrong)));
+        assert!(!credentials.check(Some("Basic !!!")));
+        assert!(!credentials.check(Some("Bearer token")));
+        assert!(!credentials.check(None));
+    }
+
+    #[test]
+    fn test_may_preview() {
+        let credentials = Credentials {
+            username: "admin".to_string(),
+            password: "secret".to_string(),
+        };
+        let header = format!("Basic {}", STANDARD.encode("admin:secret"));
+        assert_eq!(may_preview(Some(&credentials), Some(&header)), Some(true));
+        assert_eq!(may_preview(Some(&credentials), None), Some(false));
+        // Without credentials nobody previews, whatever they send
+        assert_eq!(may_preview(None, None), None);
+        assert_eq!(may_preview(None, Some(&header)), None);
+    }
+}
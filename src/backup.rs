@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, create_dir_all, rename, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusty_ulid::Ulid;
+use serde_derive::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::index::local::{DirLock, Local};
+use crate::query;
+use crate::site::Paths;
+
+// Name of the manifest, always the first entry in an archive
+pub const MANIFEST: &str = "manifest.yaml";
+
+// Bumped whenever the archive layout changes in an incompatible way
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    pub created: DateTime<Utc>,
+    pub articles: usize,
+    // Archive path of every file mapped to its BLAKE3 checksum
+    pub files: BTreeMap<String, String>,
+}
+
+// The directories making up a site, keyed by their prefix inside an archive
+fn roots(paths: &Paths) -> [(&'static str, &Path); 3] {
+    [
+        ("data", &paths.data),
+        ("templates", &paths.templates),
+        ("site", &paths.statics),
+    ]
+}
+
+fn checksum<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+// Lists the files of a site as (archive path, path on disk), skipping lock
+// files and other bookkeeping
+fn site_files(paths: &Paths) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = vec![];
+    for (prefix, root) in roots(paths) {
+        if !root.is_dir() {
+            continue;
+        }
+        for entry in WalkDir::new(root).sort_by_file_name().min_depth(1) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(root)?;
+            let hidden = relative.components().any(|c| match c {
+                Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+                _ => false,
+            });
+            if hidden {
+                continue;
+            }
+            let name = Path::new(prefix).join(relative);
+            files.push((name.to_string_lossy().into_owned(), entry.into_path()));
+        }
+    }
+    Ok(files)
+}
+
+// Writes the whole site as a gzipped tar to writer, as of the time created.
+// The data directory is locked against writers until the export is done, so
+// writer should be quick to take it all, such as a file.
+pub fn export<W: Write>(paths: &Paths, created: DateTime<Utc>, writer: W) -> Result<Manifest> {
+    let _lock = DirLock::shared(&paths.data)?;
+
+    let files = site_files(paths)?;
+    let mut manifest = Manifest {
+        version: FORMAT_VERSION,
+        created,
+        articles: Local::new(&paths.data)?.count(query::ALL)?,
+        files: BTreeMap::new(),
+    };
+    for (name, path) in files.iter() {
+        manifest
+            .files
+            .insert(name.clone(), checksum(File::open(path)?)?);
+    }
+
+    let mut archive = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+    let manifest_yaml = serde_yaml::to_string(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_yaml.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created.timestamp() as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, MANIFEST, manifest_yaml.as_bytes())?;
+
+    for (name, path) in files.iter() {
+        archive.append_path_with_name(path, name)?;
+    }
+    archive.into_inner()?.finish()?.flush()?;
+    Ok(manifest)
+}
+
+// Restores a site from an archive written by export. Everything is unpacked
+// and checked against the manifest next to the existing directories, which
+// are only swapped out once the whole archive is known to be good. As with
+// export, the caller should hold the index for the duration.
+pub fn import<R: Read>(paths: &Paths, reader: R) -> Result<Manifest> {
+    let suffix = Ulid::generate().to_string();
+    let staging: Vec<(&str, PathBuf, &Path)> = roots(paths)
+        .into_iter()
+        .map(|(prefix, root)| (prefix, sibling(root, "restore", &suffix), root))
+        .collect();
+
+    let manifest = match unpack(reader, &staging) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            discard(&staging);
+            return Err(e);
+        }
+    };
+
+    // The lock inside the data directory moves away with it, so imports are
+    // kept apart by a lock beside it as well
+    let _restoring = DirLock::exclusive_file(&sibling(&paths.data, "restore", "lock"))?;
+    let _lock = DirLock::exclusive(&paths.data)?;
+    if let Err(e) = replace_roots(&staging, &suffix) {
+        discard(&staging);
+        return Err(e);
+    }
+    Ok(manifest)
+}
+
+// Moves each staged directory into place, keeping the old ones aside until
+// all of them are in. A failure part way puts back the ones already moved.
+fn replace_roots(staging: &[(&str, PathBuf, &Path)], suffix: &str) -> Result<()> {
+    let mut replaced: Vec<(&Path, &Path, PathBuf)> = vec![];
+    for (_, stage, root) in staging.iter() {
+        let old = sibling(root, "old", suffix);
+        let moved = match root.exists() {
+            true => rename(root, &old),
+            false => Ok(()),
+        }
+        .and_then(|_| rename(stage, root));
+        if let Err(e) = moved {
+            replaced.push((stage, root, old));
+            for (stage, root, old) in replaced.iter().rev() {
+                if !stage.exists() && root.exists() {
+                    let _ = rename(root, stage);
+                }
+                if old.exists() {
+                    let _ = rename(old, root);
+                }
+            }
+            return Err(e.into());
+        }
+        replaced.push((stage, root, old));
+    }
+    for (_, _, old) in replaced {
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+    }
+    Ok(())
+}
+
+fn discard(staging: &[(&str, PathBuf, &Path)]) {
+    for (_, stage, _) in staging.iter() {
+        let _ = fs::remove_dir_all(stage);
+    }
+}
+
+fn sibling(root: &Path, kind: &str, suffix: &str) -> PathBuf {
+    let name = root
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    root.with_file_name(format!(".{}.{}-{}", name, kind, suffix))
+}
+
+fn unpack<R: Read>(reader: R, staging: &[(&str, PathBuf, &Path)]) -> Result<Manifest> {
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut entries = archive.entries()?;
+
+    let manifest: Manifest = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()?.as_ref() != Path::new(MANIFEST) {
+                bail!("archive does not start with a {}", MANIFEST);
+            }
+            let mut yaml = String::new();
+            entry.read_to_string(&mut yaml)?;
+            serde_yaml::from_str(&yaml)?
+        }
+        None => bail!("archive is empty"),
+    };
+    if manifest.version > FORMAT_VERSION {
+        bail!(
+            "archive format version {} is newer than supported version {}",
+            manifest.version,
+            FORMAT_VERSION
+        );
+    }
+
+    for (_, stage, _) in staging.iter() {
+        create_dir_all(stage)?;
+    }
+
+    let mut seen = BTreeSet::new();
+    for entry in entries {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let expected = match manifest.files.get(&name) {
+            Some(expected) => expected,
+            None => bail!("{} is not listed in the manifest", name),
+        };
+
+        let path = PathBuf::from(&name);
+        let (stage, relative) = match staging
+            .iter()
+            .find_map(|(prefix, stage, _)| path.strip_prefix(prefix).ok().map(|r| (stage, r)))
+        {
+            Some(found) => found,
+            None => bail!("{} is outside of the site directories", name),
+        };
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            bail!("{} is not a plain relative path", name);
+        }
+
+        let target = stage.join(relative);
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut file = File::create(&target)?;
+        io::copy(&mut entry, &mut file)?;
+        if &checksum(File::open(&target)?)? != expected {
+            bail!("checksum mismatch for {}", name);
+        }
+        seen.insert(name);
+    }
+
+    if let Some(missing) = manifest.files.keys().find(|name| !seen.contains(*name)) {
+        bail!("{} is listed in the manifest but missing", missing);
+    }
+
+    let (_, data_stage, _) = staging
+        .iter()
+        .find(|(prefix, _, _)| *prefix == "data")
+        .expect("data directory is always staged");
+    let articles = Local::new(data_stage)?.count(query::ALL)?;
+    if articles != manifest.articles {
+        bail!(
+            "archive holds {} articles but the manifest lists {}",
+            articles,
+            manifest.articles
+        );
+    }
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::articles::{Article, NewArticleRequest};
+    use crate::backup::*;
+    use crate::index::Index;
+    use tempdir::TempDir;
+
+    fn site(root: &Path) -> Paths {
+        Paths {
+            data: root.join("data"),
+            templates: root.join("templates"),
+            statics: root.join("site"),
+        }
+    }
+
+    fn populate(paths: &Paths) -> Local {
+        let mut index = Local::new(&paths.data).unwrap();
+        index
+            .update(&Article::new(&NewArticleRequest {
+                id: "main".to_string(),
+                body: "<p>hello</p>".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+        create_dir_all(&paths.templates).unwrap();
+        fs::write(
+            paths.templates.join("index.html.hbs"),
+            "{{article \"@main\"}}",
+        )
+        .unwrap();
+        create_dir_all(&paths.statics).unwrap();
+        fs::write(paths.statics.join("site.css"), "body {}").unwrap();
+        index
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let from_dir = TempDir::new("backup_from").unwrap();
+        let from = site(from_dir.path());
+        populate(&from);
+
+        let mut archive = vec![];
+        let manifest = export(&from, Utc::now(), &mut archive).unwrap();
+        assert_eq!(manifest.version, FORMAT_VERSION);
+        assert_eq!(manifest.articles, 1);
+        assert!(manifest.files.contains_key("templates/index.html.hbs"));
+        assert!(manifest.files.contains_key("site/site.css"));
+        assert!(!manifest.files.keys().any(|name| name.contains(".lock")));
+
+        let to_dir = TempDir::new("backup_to").unwrap();
+        let to = site(to_dir.path());
+        create_dir_all(&to.statics).unwrap();
+        fs::write(to.statics.join("stale.css"), "").unwrap();
+        assert_eq!(import(&to, &archive[..]).unwrap(), manifest);
+        let mut target = Local::new(&to.data).unwrap();
+        assert!(!to.statics.join("stale.css").exists());
+        assert_eq!(
+            fs::read_to_string(to.statics.join("site.css")).unwrap(),
+            "body {}"
+        );
+        let entry = target.first(&"@main".try_into().unwrap()).unwrap();
+        assert_eq!(entry.body_string().unwrap(), "<p>hello</p>");
+    }
+
+    #[test]
+    fn test_replace_roots_rolls_back() {
+        let dir = TempDir::new("backup_rollback").unwrap();
+        let paths = site(dir.path());
+        populate(&paths);
+        let suffix = Ulid::generate().to_string();
+        let staging: Vec<(&str, PathBuf, &Path)> = roots(&paths)
+            .into_iter()
+            .map(|(prefix, root)| (prefix, sibling(root, "restore", &suffix), root))
+            .collect();
+        // The last directory isn't staged, so moving it into place fails
+        for (_, stage, _) in staging[..2].iter() {
+            create_dir_all(stage).unwrap();
+        }
+        assert!(replace_roots(&staging, &suffix).is_err());
+
+        let mut index = Local::new(&paths.data).unwrap();
+        assert!(index.first(&"@main".try_into().unwrap()).is_ok());
+        assert!(paths.templates.join("index.html.hbs").is_file());
+        assert!(paths.statics.join("site.css").is_file());
+        for (_, _, root) in staging.iter() {
+            assert!(!sibling(root, "old", &suffix).exists());
+        }
+    }
+
+    #[test]
+    fn test_import_rejects_corrupt_archive() {
+        let from_dir = TempDir::new("backup_corrupt").unwrap();
+        let from = site(from_dir.path());
+        populate(&from);
+        let mut archive = vec![];
+        export(&from, Utc::now(), &mut archive).unwrap();
+        archive.truncate(archive.len() / 2);
+
+        let to_dir = TempDir::new("backup_corrupt_to").unwrap();
+        let to = site(to_dir.path());
+        populate(&to);
+        assert!(import(&to, &archive[..]).is_err());
+
+        // Nothing was replaced and no staging directories are left behind
+        assert!(to.statics.join("site.css").exists());
+        assert_eq!(fs::read_dir(to_dir.path()).unwrap().count(), 3);
+    }
+}
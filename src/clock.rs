@@ -0,0 +1,43 @@
+#[cfg(test)]
+use std::sync::Mutex;
+
+#[cfg(test)]
+use chrono::Duration;
+use chrono::{DateTime, Utc};
+
+// Every lookup of the current time goes through a Clock, so that tests can
+// stop time and check what happens before and after a given moment
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+// The time of the system, used when serving
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// A clock that stays at the time it was set to until it is moved on
+#[cfg(test)]
+pub struct FixedClock(Mutex<DateTime<Utc>>);
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        FixedClock(Mutex::new(now))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
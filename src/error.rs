@@ -2,6 +2,9 @@ use rocket::request::Request;
 use rocket::response::Responder;
 use rocket::{http::Status, Response};
 use std::io::ErrorKind;
+use thiserror::Error;
+
+use crate::index;
 
 #[macro_export]
 macro_rules! impl_from_error {
@@ -14,11 +17,18 @@ macro_rules! impl_from_error {
     };
 }
 
-#[derive(Debug)]
+#[derive(Error, Debug)]
 pub enum Error {
+    #[error("io error: {0}")]
     Io(std::io::Error),
-    Template(handlebars::TemplateError),
-    Render(handlebars::RenderError),
+    #[error("template error: {0}")]
+    Template(Box<handlebars::TemplateError>),
+    #[error("render error: {0}")]
+    Render(Box<handlebars::RenderError>),
+    #[error("index error: {0}")]
+    Index(anyhow::Error),
+    #[error("serialization error: {0}")]
+    Json(serde_json::Error),
 }
 
 impl<'r> Responder<'r, 'static> for Error {
@@ -28,12 +38,29 @@ impl<'r> Responder<'r, 'static> for Error {
             Error::Io(ref e) if e.kind() == ErrorKind::NotFound => {
                 response.status(Status::NotFound);
             }
-            _ => return Err(Status::InternalServerError),
+            Error::Index(ref e) if e.downcast_ref() == Some(&index::Error::ArticleNotFound) => {
+                response.status(Status::NotFound);
+            }
+            e => {
+                rocket::error_!("{}", e);
+                return Err(Status::InternalServerError);
+            }
         };
         response.ok()
     }
 }
 
 impl_from_error!(std::io::Error, Error::Io);
-impl_from_error!(handlebars::TemplateError, Error::Template);
-impl_from_error!(handlebars::RenderError, Error::Render);
+impl_from_error!(anyhow::Error, Error::Index);
+impl_from_error!(serde_json::Error, Error::Json);
+impl From<handlebars::TemplateError> for Error {
+    fn from(e: handlebars::TemplateError) -> Self {
+        Error::Template(Box::new(e))
+    }
+}
+
+impl From<handlebars::RenderError> for Error {
+    fn from(e: handlebars::RenderError) -> Self {
+        Error::Render(Box::new(e))
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashSet;
+
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag, TagEnd};
+use serde_derive::Deserialize;
+
+use crate::articles::{slug_from_title, Article};
+
+// The property naming the format of an article body, html when not set
+pub const FORMAT_PROPERTY: &str = "format";
+
+// Formats of article bodies. Every body is expanded by Handlebars first, and
+// then converted into HTML according to its format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Markdown,
+    Text,
+}
+
+// Which rendered bodies are cleaned of unsafe HTML such as scripts, set per
+// site with sanitize = "off" | "markdown" | "all"
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sanitize {
+    Off,
+    #[default]
+    Markdown,
+    All,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "html" => Some(Format::Html),
+            "markdown" => Some(Format::Markdown),
+            "text" => Some(Format::Text),
+            _ => None,
+        }
+    }
+
+    // Bodies found outside the index, such as fallback templates, are html
+    pub fn of(article: Option<&Article>) -> Self {
+        article
+            .and_then(|article| article.properties.get(FORMAT_PROPERTY))
+            .and_then(|name| Format::parse(name))
+            .unwrap_or(Format::Html)
+    }
+
+    // Converts an expanded body into HTML
+    pub fn render(self, expanded: &str, sanitize: Sanitize) -> String {
+        let html = match self {
+            Format::Html => expanded.to_string(),
+            Format::Markdown => markdown_to_html(expanded),
+            Format::Text => format!("<pre>{}</pre>", handlebars::html_escape(expanded)),
+        };
+        match (self, sanitize) {
+            (Format::Markdown, Sanitize::Markdown) | (_, Sanitize::All) => sanitize_html(&html),
+            _ => html,
+        }
+    }
+}
+
+// Renders CommonMark with tables and footnotes, giving every heading an id
+// derived from its text so that it can be linked to
+fn markdown_to_html(markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_HEADING_ATTRIBUTES);
+
+    let mut events: Vec<Event> = Parser::new_ext(markdown, options).collect();
+    let mut used = HashSet::new();
+    let mut heading = None;
+    let mut text = String::new();
+    for i in 0..events.len() {
+        match events[i] {
+            Event::Start(Tag::Heading { ref id, .. }) => {
+                if let Some(id) = id {
+                    used.insert(id.to_string());
+                }
+                heading = Some(i);
+                text.clear();
+            }
+            Event::Text(ref t) | Event::Code(ref t) if heading.is_some() => text.push_str(t),
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some(Event::Start(Tag::Heading { ref mut id, .. })) =
+                    heading.take().map(|start| &mut events[start])
+                {
+                    if id.is_none() {
+                        *id = Some(CowStr::from(unique_anchor(&text, &mut used)));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut output = String::new();
+    html::push_html(&mut output, events.into_iter());
+    output
+}
+
+fn unique_anchor(text: &str, used: &mut HashSet<String>) -> String {
+    let slug = match slug_from_title(text) {
+        slug if slug.is_empty() => "section".to_string(),
+        slug => slug,
+    };
+    let mut anchor = slug.clone();
+    let mut n = 1;
+    while !used.insert(anchor.clone()) {
+        n += 1;
+        anchor = format!("{}-{}", slug, n);
+    }
+    anchor
+}
+
+// Removes scripts, event handlers and anything else that could run in the
+// page, keeping what rendered Markdown needs for anchors and footnotes
+fn sanitize_html(html: &str) -> String {
+    let mut builder = ammonia::Builder::default();
+    for heading in ["h1", "h2", "h3", "h4", "h5", "h6"] {
+        builder.add_tag_attributes(heading, &["id"]);
+    }
+    builder
+        .add_tag_attributes("div", &["id", "class"])
+        .add_tag_attributes("sup", &["class"])
+        .add_tag_attributes("td", &["style"])
+        .add_tag_attributes("th", &["style"]);
+    builder.clean(html).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::articles::NewArticleRequest;
+    use crate::format::*;
+
+    #[test]
+    fn test_of() {
+        let mut article = Article::new(&NewArticleRequest::default());
+        assert_eq!(Format::of(None), Format::Html);
+        assert_eq!(Format::of(Some(&article)), Format::Html);
+        article
+            .properties
+            .insert(FORMAT_PROPERTY.into(), "markdown".into());
+        assert_eq!(Format::of(Some(&article)), Format::Markdown);
+        article
+            .properties
+            .insert(FORMAT_PROPERTY.into(), "rtf".into());
+        assert_eq!(Format::of(Some(&article)), Format::Html);
+    }
+
+    #[test]
+    fn test_markdown() {
+        let html = Format::Markdown.render(
+            "# Hello *World*\n\n## Hello World\n\n## Custom {#mine}\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\nNote[^1]\n\n[^1]: The note\n",
+            Sanitize::Markdown,
+        );
+        assert!(html.contains(r#"<h1 id="hello-world">Hello <em>World</em></h1>"#));
+        assert!(html.contains(r#"<h2 id="hello-world-2">Hello World</h2>"#));
+        assert!(html.contains(r#"<h2 id="mine">Custom</h2>"#));
+        assert!(html.contains("<table>"));
+        assert!(html.contains("<td>1</td>"));
+        assert!(html.contains(r#"<div class="footnote-definition" id="1">"#));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let unsafe_body = "<p onclick=\"steal()\">hi</p><script>steal()</script>";
+        let cleaned = Format::Markdown.render(unsafe_body, Sanitize::Markdown);
+        assert!(!cleaned.contains("script") && !cleaned.contains("onclick"));
+        assert!(cleaned.contains("hi"));
+
+        // Html is trusted unless every body is sanitized
+        assert_eq!(
+            Format::Html.render(unsafe_body, Sanitize::Markdown),
+            unsafe_body
+        );
+        assert!(!Format::Html
+            .render(unsafe_body, Sanitize::All)
+            .contains("script"));
+        assert!(Format::Markdown
+            .render(unsafe_body, Sanitize::Off)
+            .contains("script"));
+
+        assert_eq!(
+            Format::Text.render("a < b", Sanitize::Off),
+            "<pre>a &lt; b</pre>"
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+
+use serde_yaml::{Mapping, Value};
+
+use crate::articles::{Article, LinkKind, Status, DEFAULT_PROPERTIES};
+
+// Front matter is a YAML block at the start of a body between two --- lines,
+// as in Jekyll and Hugo. title, id, tags, aliases, status, publish_at and the
+// links parent, related, next and prev set those fields, any other key is a
+// property:
+//
+//   ---
+//   title: Hello
+//   tags: [news, blog]
+//   parent: docs
+//   price: 9.99
+//   ---
+const DELIMITER: &str = "---";
+
+// Keys of front matter that are not properties
+pub const RESERVED_KEYS: &[&str] = &[
+    "title",
+    "id",
+    "tags",
+    "aliases",
+    "status",
+    "publish_at",
+    "parent",
+    "related",
+    "next",
+    "prev",
+    "links",
+];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrontMatter {
+    pub title: Option<String>,
+    pub id: Option<String>,
+    pub tags: Vec<String>,
+    pub aliases: Vec<String>,
+    pub status: Option<String>,
+    pub publish_at: Option<String>,
+    pub links: Vec<(LinkKind, String)>,
+    pub properties: BTreeMap<String, String>,
+}
+
+// Splits the front matter from the start of body, returning the rest of the
+// body. A body that doesn't start with a closed front matter block is left
+// as it is.
+pub fn split(body: &str) -> Result<(Option<FrontMatter>, &str), String> {
+    let start = body.trim_start();
+    let (first, mut rest) = start.split_once('\n').unwrap_or((start, ""));
+    if first.trim_end() != DELIMITER {
+        return Ok((None, body));
+    }
+
+    let yaml_start = rest;
+    let mut yaml_length = 0;
+    loop {
+        let (line, remaining) = match rest.split_once('\n') {
+            Some(split) => split,
+            None if rest.is_empty() => return Ok((None, body)),
+            None => (rest, ""),
+        };
+        if line.trim_end() == DELIMITER {
+            rest = remaining;
+            break;
+        }
+        yaml_length += line.len() + 1;
+        rest = remaining;
+    }
+    let yaml = &yaml_start[..yaml_length.min(yaml_start.len())];
+    Ok((Some(parse(yaml)?), rest))
+}
+
+fn parse(yaml: &str) -> Result<FrontMatter, String> {
+    let mapping: Mapping = match serde_yaml::from_str(yaml) {
+        Ok(Value::Mapping(mapping)) => mapping,
+        Ok(Value::Null) => Mapping::new(),
+        Ok(_) => return Err("front matter must be a map of fields".into()),
+        Err(e) => return Err(format!("front matter is not valid YAML: {}", e)),
+    };
+
+    let mut front = FrontMatter::default();
+    for (key, value) in mapping {
+        let key = match key {
+            Value::String(key) => key,
+            key => scalar(&key).ok_or("front matter keys must be names")?,
+        };
+        match key.as_str() {
+            "title" => front.title = Some(expect_scalar(&key, &value)?),
+            "id" => front.id = Some(expect_scalar(&key, &value)?),
+            "tags" => front.tags = words(&key, &value)?,
+            "aliases" => front.aliases = words(&key, &value)?,
+            "status" => front.status = Some(expect_scalar(&key, &value)?),
+            "publish_at" => front.publish_at = Some(expect_scalar(&key, &value)?),
+            _ => match LinkKind::parse(&key) {
+                Some(LinkKind::Related) => {
+                    for target in words(&key, &value)? {
+                        front.links.push((LinkKind::Related, target));
+                    }
+                }
+                Some(kind) => front.links.push((kind, expect_scalar(&key, &value)?)),
+                None => {
+                    let value = expect_scalar(&key, &value)?;
+                    front.properties.insert(key, value);
+                }
+            },
+        }
+    }
+    Ok(front)
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn expect_scalar(key: &str, value: &Value) -> Result<String, String> {
+    scalar(value).ok_or_else(|| format!("front matter {} must be a single value", key))
+}
+
+// Lists may be written as YAML sequences or as space separated words
+fn words(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::Sequence(values) => values.iter().map(|v| expect_scalar(key, v)).collect(),
+        Value::Null => Ok(vec![]),
+        value => Ok(expect_scalar(key, value)?
+            .split_whitespace()
+            .map(String::from)
+            .collect()),
+    }
+}
+
+// Renders the front matter of an article followed by its body, for editing
+pub fn render(article: &Article, body: &str) -> String {
+    let mut mapping = Mapping::new();
+    if !article.title.is_empty() {
+        mapping.insert("title".into(), article.title.clone().into());
+    }
+    let sorted = |values: &mut dyn Iterator<Item = &String>| {
+        let mut values: Vec<Value> = values.cloned().map(Value::from).collect();
+        values.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+        Value::Sequence(values)
+    };
+    if !article.tags.is_empty() {
+        mapping.insert("tags".into(), sorted(&mut article.tags.iter()));
+    }
+    if !article.aliases.is_empty() {
+        mapping.insert("aliases".into(), sorted(&mut article.aliases.iter()));
+    }
+    if article.status != Status::default() {
+        mapping.insert("status".into(), article.status.name().into());
+    }
+    if let Some(publish_at) = article.publish_at {
+        mapping.insert("publish_at".into(), publish_at.to_rfc3339().into());
+    }
+    for (kind, targets) in article.links.iter() {
+        let value = match kind.is_single() {
+            true => targets.iter().next().cloned().map(Value::from),
+            false => Some(sorted(&mut targets.iter())),
+        };
+        if let Some(value) = value {
+            mapping.insert(kind.name().into(), value);
+        }
+    }
+    let mut properties: Vec<(&String, &String)> = article
+        .properties
+        .iter()
+        .filter(|(name, _)| !DEFAULT_PROPERTIES.contains(&name.as_str()))
+        .collect();
+    properties.sort();
+    for (name, value) in properties {
+        mapping.insert(name.clone().into(), value.clone().into());
+    }
+
+    if mapping.is_empty() {
+        return body.to_string();
+    }
+    let yaml = serde_yaml::to_string(&mapping).unwrap_or_default();
+    format!("{}\n{}{}\n{}", DELIMITER, yaml, DELIMITER, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::articles::{Links, NewArticleRequest};
+    use crate::front_matter::*;
+
+    #[test]
+    fn test_split() {
+        let body = "---\ntitle: Hello\ntags: [news, blog]\naliases: hi hey\nprice: 9.99\npublished: true\n---\n<p>Hi</p>\n";
+        let (front, rest) = split(body).unwrap();
+        let front = front.unwrap();
+        assert_eq!(front.title.as_deref(), Some("Hello"));
+        assert_eq!(front.tags, ["news", "blog"]);
+        assert_eq!(front.aliases, ["hi", "hey"]);
+        assert_eq!(front.properties["price"], "9.99");
+        assert_eq!(front.properties["published"], "true");
+        assert_eq!(rest, "<p>Hi</p>\n");
+
+        // Leading whitespace, as left by a textarea, and an empty block
+        let (front, rest) = split("\n  ---\n---\nbody").unwrap();
+        assert_eq!(front, Some(FrontMatter::default()));
+        assert_eq!(rest, "body");
+
+        // Without a closing line there is no front matter
+        assert_eq!(split("---\nbody").unwrap(), (None, "---\nbody"));
+        assert_eq!(split("body\n---\n").unwrap(), (None, "body\n---\n"));
+
+        assert!(split("---\ntitle: [a\n---\n").is_err());
+        assert!(split("---\n- a\n---\n").is_err());
+        assert!(split("---\nprice: {a: 1}\n---\n").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let mut article = Article::new(&NewArticleRequest {
+            title: "Hello: World".to_string(),
+            tags: "news blog".to_string(),
+            properties: "price:9.99".to_string(),
+            ..Default::default()
+        });
+        let rendered = render(&article, "<p>Hi</p>");
+        assert!(rendered.starts_with("---\ntitle: 'Hello: World'\ntags:\n- blog\n- news\n"));
+        assert!(rendered.ends_with("---\n<p>Hi</p>"));
+        assert!(!rendered.contains("timestamp"));
+
+        // Rendered front matter reads back into the same article
+        let (front, rest) = split(&rendered).unwrap();
+        let front = front.unwrap();
+        assert_eq!(front.title.unwrap(), article.title);
+        assert_eq!(front.tags, ["blog", "news"]);
+        assert_eq!(front.properties["price"], "9.99");
+        assert_eq!(rest, "<p>Hi</p>");
+
+        article.links = Links::from([
+            (LinkKind::Parent, ["docs".to_string()].into()),
+            (LinkKind::Related, ["b".to_string(), "a".to_string()].into()),
+        ]);
+        let rendered = render(&article, "");
+        assert!(rendered.contains("parent: docs\nrelated:\n- a\n- b\n"));
+        let (front, _) = split(&rendered).unwrap();
+        assert_eq!(
+            front.unwrap().links,
+            [
+                (LinkKind::Parent, "docs".to_string()),
+                (LinkKind::Related, "a".to_string()),
+                (LinkKind::Related, "b".to_string())
+            ]
+        );
+
+        article.links.clear();
+        article.title.clear();
+        article.tags.clear();
+        article.properties.remove("price");
+        assert_eq!(render(&article, "body"), "body");
+    }
+}
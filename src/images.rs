@@ -0,0 +1,341 @@
+use std::fs::{self, create_dir_all, File};
+use std::io::{BufReader, ErrorKind};
+use std::path::Path;
+
+use anyhow::Result;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader};
+use rocket::form::{FromForm, FromFormField};
+use rocket::http::ContentType;
+use rusty_ulid::Ulid;
+
+use crate::index::local::DirLock;
+use crate::media::{content_path, derivatives_dir, is_hash};
+
+// Images uploaded as media can be served resized or converted, such as
+// /media/<hash>?w=640&fmt=webp. Each derivative is made the first time it is
+// asked for and kept under data/media/derivatives until its content goes.
+
+// Widths the srcset helper offers when none are given. Images are only
+// resized to these and to the image_widths a site adds, so that anyone asking
+// can't have countless derivatives made of every image.
+pub const DEFAULT_WIDTHS: &[u32] = &[320, 640, 1280];
+
+// Whether images may be resized to size, given the widths a site adds
+pub fn is_allowed_size(size: u32, widths: &[u32]) -> bool {
+    DEFAULT_WIDTHS.contains(&size) || widths.contains(&size)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, FromFormField)]
+pub enum Fit {
+    // Fits the image within the width and height, keeping its proportions
+    #[default]
+    Scale,
+    // Fills the width and height exactly, cutting off what doesn't fit
+    Crop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromFormField)]
+pub enum Format {
+    #[field(value = "jpeg")]
+    #[field(value = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "jpeg" | "jpg" => Some(Format::Jpeg),
+            "png" => Some(Format::Png),
+            "webp" => Some(Format::Webp),
+            "gif" => Some(Format::Gif),
+            _ => None,
+        }
+    }
+
+    fn from_image(format: ImageFormat) -> Option<Self> {
+        match format {
+            ImageFormat::Jpeg => Some(Format::Jpeg),
+            ImageFormat::Png => Some(Format::Png),
+            ImageFormat::WebP => Some(Format::Webp),
+            ImageFormat::Gif => Some(Format::Gif),
+            _ => None,
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            Format::Jpeg => ImageFormat::Jpeg,
+            Format::Png => ImageFormat::Png,
+            Format::Webp => ImageFormat::WebP,
+            Format::Gif => ImageFormat::Gif,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Gif => "gif",
+        }
+    }
+
+    pub fn content_type(self) -> ContentType {
+        match self {
+            Format::Jpeg => ContentType::JPEG,
+            Format::Png => ContentType::PNG,
+            Format::Webp => ContentType::WEBP,
+            Format::Gif => ContentType::GIF,
+        }
+    }
+}
+
+// How an image should be served, taken from the query of a media URL. Images
+// are never made larger than they were uploaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, FromForm)]
+pub struct Transform {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fit: Option<Fit>,
+    pub fmt: Option<Format>,
+}
+
+impl Transform {
+    pub fn is_empty(&self) -> bool {
+        self == &<Transform as Default>::default()
+    }
+
+    // Both the width and height must be allowed sizes, see DEFAULT_WIDTHS
+    fn is_allowed(&self, widths: &[u32]) -> bool {
+        [self.w, self.h]
+            .into_iter()
+            .flatten()
+            .all(|size| is_allowed_size(size, widths))
+    }
+
+    // The size to make an image of width by height, or None to leave it be
+    fn size(&self, width: u32, height: u32) -> Option<(u32, u32, Fit)> {
+        let w = self.w.unwrap_or(width).clamp(1, width);
+        let h = self.h.unwrap_or(height).clamp(1, height);
+        // Cropping needs both sides, otherwise there is nothing to cut
+        let fit = match (self.fit, self.w, self.h) {
+            (Some(Fit::Crop), Some(_), Some(_)) => Fit::Crop,
+            _ => Fit::Scale,
+        };
+        match (w, h) == (width, height) {
+            true => None,
+            false => Some((w, h, fit)),
+        }
+    }
+}
+
+// Guesses the type of the content under hash from its first bytes, for media
+// URLs without a name
+pub fn sniff(data: &Path, hash: &str) -> Result<ContentType> {
+    let _lock = DirLock::shared(data)?;
+    let reader = ImageReader::new(BufReader::new(File::open(content_path(data, hash))?))
+        .with_guessed_format()?;
+    Ok(reader
+        .format()
+        .and_then(Format::from_image)
+        .map_or(ContentType::Binary, Format::content_type))
+}
+
+// Opens the content under hash transformed, making and caching the derivative
+// if needed. Returns None when there is no such content, it isn't an image or
+// the size asked for isn't among widths or the defaults.
+pub fn derive(
+    data: &Path,
+    hash: &str,
+    transform: &Transform,
+    widths: &[u32],
+) -> Result<Option<(File, Format)>> {
+    if !is_hash(hash) || !transform.is_allowed(widths) {
+        return Ok(None);
+    }
+    let hash = hash.to_ascii_lowercase();
+    // Derivatives are written under a shared lock, as removing content takes
+    // the lock exclusively
+    let _lock = DirLock::shared(data)?;
+    let source = content_path(data, &hash);
+    let reader = match File::open(&source) {
+        Ok(file) => ImageReader::new(BufReader::new(file)).with_guessed_format()?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let original = match reader.format().and_then(Format::from_image) {
+        Some(format) => format,
+        None => return Ok(None),
+    };
+    let format = transform.fmt.unwrap_or(original);
+    let (width, height) = reader.into_dimensions()?;
+    let size = transform.size(width, height);
+    if size.is_none() && format == original {
+        return Ok(Some((File::open(&source)?, format)));
+    }
+
+    let (w, h, fit) = size.unwrap_or((width, height, Fit::Scale));
+    let name = format!("{}x{}-{:?}.{}", w, h, fit, format.extension()).to_lowercase();
+    let dir = derivatives_dir(data, &hash);
+    let path = dir.join(&name);
+    match File::open(&path) {
+        Ok(file) => return Ok(Some((file, format))),
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        Err(_) => {}
+    }
+
+    let mut image = ImageReader::open(&source)?
+        .with_guessed_format()?
+        .decode()?;
+    if size.is_some() {
+        image = match fit {
+            Fit::Scale => image.resize(w, h, FilterType::Lanczos3),
+            Fit::Crop => image.resize_to_fill(w, h, FilterType::Lanczos3),
+        };
+    }
+    // JPEG has no transparency, the other formats are written with it
+    let image = match format {
+        Format::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+        _ => DynamicImage::ImageRgba8(image.to_rgba8()),
+    };
+    // Written aside and moved into place, so a derivative is never read half
+    // written by another request
+    create_dir_all(&dir)?;
+    let staged = dir.join(format!(".{}-{}", name, Ulid::generate()));
+    image.save_with_format(&staged, format.image_format())?;
+    fs::rename(&staged, &path)?;
+    Ok(Some((File::open(&path)?, format)))
+}
+
+// The srcset attribute offering url at each of widths
+pub fn srcset(url: &str, widths: &[u32], format: Option<Format>) -> String {
+    widths
+        .iter()
+        .map(|width| format!("{} {}w", variant_url(url, *width, format), width))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn variant_url(url: &str, width: u32, format: Option<Format>) -> String {
+    match format {
+        Some(format) => format!("{}?w={}&fmt={}", url, width, format.extension()),
+        None => format!("{}?w={}", url, width),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::images::*;
+    use crate::media::{remove_article, staging_path, store};
+    use chrono::Utc;
+    use image::{Rgba, RgbaImage};
+    use std::io::Read;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_transform_size() {
+        let transform = |w, h, fit| Transform {
+            w,
+            h,
+            fit,
+            fmt: None,
+        };
+        assert_eq!(transform(None, None, None).size(100, 50), None);
+        assert_eq!(
+            transform(Some(40), None, None).size(100, 50),
+            Some((40, 50, Fit::Scale))
+        );
+        // Never larger than the original
+        assert_eq!(transform(Some(400), None, None).size(100, 50), None);
+        assert_eq!(
+            transform(Some(40), None, Some(Fit::Crop)).size(100, 50),
+            Some((40, 50, Fit::Scale))
+        );
+        assert_eq!(
+            transform(Some(40), Some(40), Some(Fit::Crop)).size(100, 50),
+            Some((40, 40, Fit::Crop))
+        );
+    }
+
+    #[test]
+    fn test_derive() {
+        let dir = TempDir::new("images_test").unwrap();
+        let data = dir.path();
+        let article = Ulid::generate();
+        let staged = staging_path(data);
+        create_dir_all(staged.parent().unwrap()).unwrap();
+        RgbaImage::from_pixel(40, 20, Rgba([200, 10, 10, 255]))
+            .save_with_format(&staged, ImageFormat::Png)
+            .unwrap();
+        let media = store(data, article, "red.png", &staged, Utc::now()).unwrap();
+        assert_eq!(sniff(data, &media.hash).unwrap(), ContentType::PNG);
+
+        let transform = Transform {
+            w: Some(10),
+            fmt: Some(Format::Webp),
+            ..Default::default()
+        };
+        let widths = [8, 10];
+        let (mut file, format) = derive(data, &media.hash, &transform, &widths)
+            .unwrap()
+            .unwrap();
+        assert_eq!(format, Format::Webp);
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes).unwrap();
+        let image = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((image.width(), image.height()), (10, 5));
+        let cached = derivatives_dir(data, &media.hash).join("10x20-scale.webp");
+        assert!(cached.is_file());
+
+        let crop = Transform {
+            w: Some(8),
+            h: Some(8),
+            fit: Some(Fit::Crop),
+            ..Default::default()
+        };
+        derive(data, &media.hash, &crop, &widths).unwrap().unwrap();
+        assert!(derivatives_dir(data, &media.hash)
+            .join("8x8-crop.png")
+            .is_file());
+
+        assert!(derive(data, &"0".repeat(64), &transform, &widths)
+            .unwrap()
+            .is_none());
+        assert!(derive(data, "../..", &transform, &widths)
+            .unwrap()
+            .is_none());
+
+        // Only the sizes of the site and the defaults are made
+        assert!(derive(data, &media.hash, &transform, &[])
+            .unwrap()
+            .is_none());
+        let odd = Transform {
+            w: Some(10),
+            h: Some(9),
+            ..Default::default()
+        };
+        assert!(derive(data, &media.hash, &odd, &widths).unwrap().is_none());
+        let default = Transform {
+            w: Some(320),
+            ..Default::default()
+        };
+        assert!(derive(data, &media.hash, &default, &[]).unwrap().is_some());
+
+        // Derivatives go with their content
+        remove_article(data, &article).unwrap();
+        assert!(!derivatives_dir(data, &media.hash).exists());
+    }
+
+    #[test]
+    fn test_srcset() {
+        assert_eq!(
+            srcset("/media/ab/a.png", &[320, 640], Some(Format::Webp)),
+            "/media/ab/a.png?w=320&fmt=webp 320w, /media/ab/a.png?w=640&fmt=webp 640w"
+        );
+        assert_eq!(srcset("/media/ab", &[10], None), "/media/ab?w=10 10w");
+    }
+}
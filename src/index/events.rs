@@ -0,0 +1,208 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rusty_ulid::Ulid;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::articles::Article;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Removed,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ChangeEvent {
+    // Increases by one with every change, consumers resume from the last
+    // sequence they have seen
+    pub sequence: u64,
+    pub kind: ChangeKind,
+    pub key: Ulid,
+    pub id: String,
+    pub tags: BTreeSet<String>,
+    pub at: DateTime<Utc>,
+}
+
+// EventLog persists changes as JSON lines and fans them out to in-process
+// subscribers. Appends must happen while the data directory is exclusively
+// locked so sequences stay unique across processes.
+pub struct EventLog {
+    path: PathBuf,
+    subscribers: Vec<Sender<ChangeEvent>>,
+}
+
+impl EventLog {
+    pub fn new(path: PathBuf) -> Self {
+        EventLog {
+            path,
+            subscribers: vec![],
+        }
+    }
+
+    pub fn append(
+        &mut self,
+        kind: ChangeKind,
+        article: &Article,
+        at: DateTime<Utc>,
+    ) -> Result<ChangeEvent> {
+        let event = ChangeEvent {
+            sequence: self.last_sequence()? + 1,
+            kind,
+            key: article.key,
+            id: article.id.clone(),
+            tags: article.tags.iter().cloned().collect(),
+            at,
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_string(&event)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+
+        // Subscribers that hung up are dropped
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        Ok(event)
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    // Returns every persisted event with a sequence greater than after
+    pub fn since(&self, after: u64) -> Result<Vec<ChangeEvent>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut events = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let event: ChangeEvent = serde_json::from_str(&line)?;
+            if event.sequence > after {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    // Reads the sequence of the final line without reading the whole log
+    fn last_sequence(&self) -> Result<u64> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let length = file.metadata()?.len();
+        let mut tail_length = 1024;
+        loop {
+            let start = length.saturating_sub(tail_length);
+            file.seek(SeekFrom::Start(start))?;
+            // Read as bytes, as the tail may start within a character
+            let mut tail = vec![];
+            file.read_to_end(&mut tail)?;
+            let mut lines = tail
+                .split(|byte| *byte == b'\n')
+                .filter(|line| !line.is_empty());
+            match lines.next_back() {
+                None => return Ok(0),
+                // The first line of the tail may be cut short, unless the
+                // tail is the whole file
+                Some(line) if start == 0 || lines.next().is_some() => {
+                    let event: ChangeEvent = serde_json::from_slice(line)?;
+                    return Ok(event.sequence);
+                }
+                Some(_) => tail_length *= 2,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::articles::NewArticleRequest;
+    use crate::index::events::*;
+    use chrono::TimeZone;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_event_log() {
+        let dir = TempDir::new("event_log_test").unwrap();
+        let mut log = EventLog::new(dir.path().join("events.log"));
+        assert!(log.since(0).unwrap().is_empty());
+
+        let subscriber = log.subscribe();
+        let at = Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap();
+        let article = Article::new(&NewArticleRequest {
+            id: "main".to_string(),
+            tags: "news".to_string(),
+            ..Default::default()
+        });
+        log.append(ChangeKind::Created, &article, at).unwrap();
+        log.append(ChangeKind::Updated, &article, at).unwrap();
+
+        let received: Vec<ChangeEvent> = subscriber.try_iter().collect();
+        assert_eq!(2, received.len());
+        assert_eq!(received[0].kind, ChangeKind::Created);
+        assert_eq!(received[0].id, "main");
+        assert!(received[0].tags.contains("news"));
+        assert_eq!(received[0].at, at);
+
+        // A new log over the same file carries on from the persisted sequence
+        drop(subscriber);
+        let mut log = EventLog::new(dir.path().join("events.log"));
+        let removed = log.append(ChangeKind::Removed, &article, at).unwrap();
+        assert_eq!(removed.sequence, 3);
+        assert_eq!(log.since(0).unwrap().len(), 3);
+        assert_eq!(log.since(2).unwrap(), vec![removed]);
+    }
+
+    #[test]
+    fn test_last_sequence_long_lines() {
+        let dir = TempDir::new("event_log_long_test").unwrap();
+        let mut log = EventLog::new(dir.path().join("events.log"));
+        let article = Article::new(&NewArticleRequest {
+            id: "x".repeat(3000),
+            ..Default::default()
+        });
+        for _ in 0..3 {
+            log.append(ChangeKind::Created, &article, Utc::now())
+                .unwrap();
+        }
+        assert_eq!(log.last_sequence().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_last_sequence_multibyte_ids() {
+        let dir = TempDir::new("event_log_multibyte_test").unwrap();
+        let mut log = EventLog::new(dir.path().join("events.log"));
+        // Lines of different lengths, so that some tails start within a
+        // character
+        for (appended, length) in (100..110).enumerate() {
+            let article = Article::new(&NewArticleRequest {
+                id: "日".repeat(length),
+                ..Default::default()
+            });
+            log.append(ChangeKind::Created, &article, Utc::now())
+                .unwrap();
+            assert_eq!(log.last_sequence().unwrap(), appended as u64 + 1);
+        }
+    }
+}
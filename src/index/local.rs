@@ -1,158 +1,237 @@
-use std::fs::{self, create_dir_all, remove_dir, rename, File};
-use std::io::Write;
+use std::collections::BTreeSet;
+use std::fs::{self, create_dir_all, rename, File, OpenOptions};
+use std::io::{ErrorKind, Write};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Result};
 use chrono::{DateTime, Utc};
+use fs2::FileExt;
 use rusty_ulid::Ulid;
 use serde_yaml;
 use walkdir::WalkDir;
 
-use crate::articles::Article;
-use crate::index::{Entry, Index};
-use crate::query::Query;
+use crate::articles::{
+    content_hash, normalize_id, slug_from_title, validate_id, Article, LinkKind,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::index::events::{ChangeEvent, ChangeKind, EventLog};
+use crate::index::{Entry, Error, Index};
+use crate::query::{LinkFilter, Query};
+use crate::schema;
+use crate::validation::ValidationErrors;
+
+// Name of the advisory lock file kept in the root of the data directory
+const LOCK_FILE: &str = ".lock";
+
+// The id generated for articles whose title gives no slug
+const UNTITLED_SLUG: &str = "untitled";
+
+// How many times a reader restarts a trie lookup that raced with a split
+const TRIE_LOOKUP_ATTEMPTS: usize = 8;
 
 pub struct Local {
     path: PathBuf,
+    events: EventLog,
+    fold_case: bool,
+    clock: Arc<dyn Clock>,
 }
 
 impl Local {
     pub fn new<T: Into<PathBuf> + AsRef<Path>>(path: T) -> Result<Self> {
-        Ok(Local { path: path.into() })
+        let path = path.into();
+        Ok(Local {
+            events: EventLog::new(path.join("events.log")),
+            path,
+            fold_case: false,
+            clock: Arc::new(SystemClock),
+        })
     }
-}
 
-pub struct LocalIterator {
-    path: PathBuf,
-    query: Query,
-    articles_walker: walkdir::IntoIter,
-    id_walker: walkdir::IntoIter,
-}
+    // Changes are recorded at the time of clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
 
-impl LocalIterator {
-    fn load_article(&mut self, key: &Ulid) -> Result<Article> {
-        eprintln!("load_article({:?})", key);
-        let mut walker = WalkDir::new(&self.path.join("index/key"))
-            .sort_by_file_name()
-            .min_depth(1)
-            .into_iter();
-        let mut search = key.to_string();
-        loop {
-            if let Some(entry) = walker.next() {
-                let entry = entry?;
-                if entry.file_type().is_file() {
-                    continue;
-                }
+    // With case insensitive ids, @Café and @café find the same article. Ids
+    // keep the case they were given, only the id index is folded.
+    pub fn case_insensitive_ids(mut self, enabled: bool) -> Self {
+        self.fold_case = enabled;
+        self
+    }
 
-                dbg!(&entry);
-                match common_prefix(entry.file_name().to_str().unwrap(), &search) {
-                    (_, _, remainder) if remainder.is_empty() => {
-                        dbg!(&remainder);
-                        let article: Article = serde_yaml::from_str(&fs::read_to_string(
-                            entry.path().join("meta.yaml"),
-                        )?)?;
-                        return Ok(article);
-                    }
-                    (prefix, _, remainder) if !prefix.is_empty() && !remainder.is_empty() => {
-                        dbg!(&prefix, &remainder);
-                        search = remainder.to_owned();
-                        continue;
-                    }
-                    (a, b, remainder) if b.is_empty() && remainder.is_empty() => {
-                        dbg!(&a, &b, &remainder);
-                        continue;
-                    }
-                    m => unreachable!("attempted to lookup empty limb in trie {:?}", m),
-                }
+    // The id followed by the aliases of an article
+    fn names<'a>(&self, article: &'a Article) -> impl Iterator<Item = &'a String> {
+        std::iter::once(&article.id).chain(article.aliases.iter())
+    }
+
+    // The keys of the id and every alias of an article in the id trie
+    fn ids<'a>(&'a self, article: &'a Article) -> impl Iterator<Item = String> + 'a {
+        self.names(article).map(|id| id_key(id, self.fold_case))
+    }
+
+    // The root of the trie of links of kind, keyed by the id linked to
+    fn links_root(&self, kind: LinkKind) -> PathBuf {
+        self.path.join("index/links").join(kind.name())
+    }
 
-                // let entry_path = entry.path();
-                // dbg!(&entry_path);
-                // let article: Article = serde_yaml::from_str(&fs::read_to_string(&entry_path)?)?;
-                // return Ok(article);
-            } else {
-                todo!("not found");
+    // Links name articles by id, which may since have been renamed, so a link
+    // to any name of the article counts
+    fn linked_names(&self, ids: &[String]) -> Result<Vec<String>> {
+        let mut names = ids.to_vec();
+        for id in ids {
+            let key = match read_key(&self.path.join("index/id"), &id_key(id, self.fold_case))? {
+                Some(key) => key,
+                None => continue,
+            };
+            if let Some(article) = read_meta(&self.path, &key)? {
+                names.extend(self.names(&article).cloned());
             }
         }
+        names.sort();
+        names.dedup();
+        Ok(names)
     }
 
-    fn load_article_body(&mut self, key: &Ulid) -> Result<String> {
-        eprintln!("load_article_body({:?})", key);
-        let mut walker = WalkDir::new(&self.path.join("articles"))
-            .sort_by_file_name()
-            .min_depth(1)
-            .into_iter();
-        for entry in walker {
-            let entry = entry?;
-            if !entry.file_type().is_file() {
-                continue;
+    // The keys of the articles with a link matching filter, in the order the
+    // articles trie would list them
+    fn linked_keys(&self, filter: &LinkFilter) -> Result<Vec<Ulid>> {
+        let mut keys = BTreeSet::new();
+        for kind in filter.kinds() {
+            for id in filter.ids.iter() {
+                let node = lookup_dir_trie(&self.links_root(kind), &id_key(id, self.fold_case))?;
+                for entry in node.map(fs::read_dir).transpose()?.into_iter().flatten() {
+                    let entry = entry?;
+                    if entry.file_type()?.is_file() {
+                        keys.insert(key_from_filename(&entry.path())?);
+                    }
+                }
             }
-
-            dbg!(&entry);
-            let entry_path = entry.path();
-            let found_key: Ulid = entry_path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .strip_suffix(".html.hbs")
-                .unwrap()
-                .parse()?;
-            if key == &found_key {
-                dbg!(&key, &found_key);
-                return fs::read_to_string(entry_path).map_err(anyhow::Error::from);
+        }
+        let mut dated = vec![];
+        for key in keys {
+            if let Some(article) = read_meta(&self.path, &key)? {
+                let created: DateTime<Utc> = article.timestamp().parse()?;
+                dated.push((datetime_to_filename(&created), key.to_string(), key));
             }
         }
-        Err(anyhow!("article with key {} not found", key))
+        dated.sort();
+        Ok(dated.into_iter().map(|(_, _, key)| key).collect())
+    }
+}
+
+// The key an id is stored under in the id trie, always in NFC so that
+// composed and decomposed forms of the same text find the same article. The /
+// of hierarchical ids is escaped, so they stay a single node in the trie
+// rather than nesting directories, and so is the ., as splitting v1.2 off v1
+// would otherwise make nodes named . or .2.
+fn id_key(id: &str, fold_case: bool) -> String {
+    let id = normalize_id(id);
+    let id = if fold_case { id.to_lowercase() } else { id };
+    id.replace('%', "%25")
+        .replace('/', "%2F")
+        .replace('.', "%2E")
+}
+
+// DirLock is an advisory lock over a whole data directory, so that several ota
+// processes (e.g. a server and an import) can safely share one. Writers hold
+// it exclusively, readers share it. The lock is released when dropped.
+pub(crate) struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    fn open(root: &Path) -> Result<File> {
+        create_dir_all(root)?;
+        Self::open_file(&root.join(LOCK_FILE))
+    }
+
+    fn open_file(path: &Path) -> Result<File> {
+        Ok(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?)
+    }
+
+    pub(crate) fn shared(root: &Path) -> Result<Self> {
+        let file = Self::open(root)?;
+        file.lock_shared()?;
+        Ok(DirLock { file })
+    }
+
+    pub(crate) fn exclusive(root: &Path) -> Result<Self> {
+        let file = Self::open(root)?;
+        file.lock_exclusive()?;
+        Ok(DirLock { file })
+    }
+
+    // Locks a file of its own rather than the one inside a directory, for
+    // work that moves the directory itself
+    pub(crate) fn exclusive_file(path: &Path) -> Result<Self> {
+        let file = Self::open_file(path)?;
+        file.lock_exclusive()?;
+        Ok(DirLock { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+pub struct LocalIterator {
+    path: PathBuf,
+    query: Query,
+    articles_walker: walkdir::IntoIter,
+    // Keys found in the link index, when the query has a link filter
+    linked: Option<std::vec::IntoIter<Ulid>>,
+    id_searched: bool,
+    fold_case: bool,
+    _lock: DirLock,
+}
+
+impl LocalIterator {
+    fn load_entry(&mut self, key: &Ulid) -> Result<LocalEntry> {
+        read_entry(&self.path, key)?.ok_or_else(|| anyhow!("article with key {} not found", key))
     }
 
     fn try_next(&mut self) -> Result<Option<<LocalIterator as Iterator>::Item>> {
         eprintln!("try_next({:?})", self.query);
         if let Some(ref id) = self.query.id {
-            loop {
-                if let Some(entry) = self.id_walker.next() {
-                    let entry = match entry {
-                        Ok(e) => e,
-                        Err(err) => {
-                            if let Some(e) = err.io_error() {
-                                // Special case: no index exists on disk
-                                if e.kind() == std::io::ErrorKind::NotFound {
-                                    return Ok(None);
-                                }
-                            }
-                            return Err(err.into());
-                        }
-                    };
-                    if !entry.file_type().is_dir() {
-                        continue;
-                    }
+            if self.id_searched {
+                return Ok(None);
+            }
+            self.id_searched = true;
 
-                    dbg!(&entry);
-                    match common_prefix(entry.file_name().to_str().unwrap(), id) {
-                        (_, _, remainder) if remainder.is_empty() => {
-                            dbg!(&remainder);
-                            let key: Ulid =
-                                fs::read_to_string(dbg!(entry.path().join("key.txt")))?.parse()?;
-                            let mut article = self.load_article(&key)?;
-                            article.body = self.load_article_body(&key)?;
-                            return Ok(Some(Box::new(LocalEntry {
-                                article,
-                                path: entry.path().to_owned(),
-                            })));
-                        }
-                        m => unreachable!("attempted to lookup empty limb in trie {:?}", m),
-                    }
-                } else {
-                    return Ok(None);
+            let id = id_key(id, self.fold_case);
+            let key = match read_key(&self.path.join("index/id"), &id)? {
+                Some(key) => key,
+                None => return Ok(None),
+            };
+            let entry = self.load_entry(&key)?;
+            // The id index has already matched the id, which may differ in case
+            if !self.query.matches_filters(&entry.article) {
+                return Ok(None);
+            }
+            Ok(Some(Box::new(entry)))
+        } else if self.linked.is_some() {
+            loop {
+                let key = match self.linked.as_mut().and_then(Iterator::next) {
+                    Some(key) => key,
+                    None => return Ok(None),
+                };
+                let entry = self.load_entry(&key)?;
+                if self.query.matches(&entry.article) {
+                    return Ok(Some(Box::new(entry)));
                 }
             }
 
-            // Tags specified
-        } else if !self.query.tags.is_empty() {
-            eprintln!("Searching for tags {:?}", self.query.tags);
-
-            return Ok(None);
-
-            // All case
+            // Everything else is filtered from all articles
         } else {
             loop {
                 if let Some(entry) = self.articles_walker.next() {
@@ -172,21 +251,12 @@ impl LocalIterator {
                     if !entry.file_type().is_file() {
                         continue;
                     }
-                    let entry_path = entry.path();
-                    let key: Ulid = entry_path
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .strip_suffix(".html.hbs")
-                        .unwrap()
-                        .parse()?;
-                    let mut article = self.load_article(&key)?;
-                    article.body = fs::read_to_string(entry_path)?;
-                    return Ok(Some(Box::new(LocalEntry {
-                        article,
-                        path: entry_path.to_path_buf(),
-                    })));
+                    let key = key_from_filename(entry.path())?;
+                    let entry = self.load_entry(&key)?;
+                    if !self.query.matches(&entry.article) {
+                        continue;
+                    }
+                    return Ok(Some(Box::new(entry)));
                 } else {
                     return Ok(None);
                 }
@@ -203,6 +273,8 @@ impl Iterator for LocalIterator {
     }
 }
 
+// LocalEntry only carries the article metadata, the body stays on disk at path
+// until it is asked for
 struct LocalEntry {
     path: PathBuf,
     article: Article,
@@ -222,82 +294,373 @@ fn datetime_to_filename(time: &DateTime<Utc>) -> String {
     format!("{}", time.format("%Y%m%d%H%M%S.%f"))
 }
 
+// Entries in the articles trie are named after the article key, legacy ones
+// also carry the body and so end in .html.hbs
+fn key_from_filename(path: &Path) -> Result<Ulid> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("invalid article entry {:?}", path))?;
+    Ok(name.strip_suffix(".html.hbs").unwrap_or(name).parse()?)
+}
+
+// Bodies live under content/, fanned out by the first two characters of
+// their hash to keep directories small
+fn content_path(root: &Path, hash: &str) -> PathBuf {
+    root.join("content").join(&hash[..2]).join(hash)
+}
+
+// Reads the metadata stored for key in the key index
+fn read_meta(root: &Path, key: &Ulid) -> Result<Option<Article>> {
+    let path = match lookup_dir_trie(&root.join("index/key"), &key.to_string())? {
+        Some(path) => path.join("meta.yaml"),
+        None => return Ok(None),
+    };
+    match fs::read_to_string(path) {
+        Ok(yaml) => Ok(Some(serde_yaml::from_str(&yaml)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Reads the article stored under key along with where its body is
+fn read_entry(root: &Path, key: &Ulid) -> Result<Option<LocalEntry>> {
+    let article = match read_meta(root, key)? {
+        Some(article) => article,
+        None => return Ok(None),
+    };
+    let path = if article.hash.is_empty() {
+        find_legacy_body(root, key)?
+    } else {
+        content_path(root, &article.hash)
+    };
+    Ok(Some(LocalEntry { article, path }))
+}
+
+// Articles written before bodies were content addressed keep their body in
+// the articles trie, named after their key
+fn find_legacy_body(root: &Path, key: &Ulid) -> Result<PathBuf> {
+    let walker = WalkDir::new(root.join("articles"))
+        .sort_by_file_name()
+        .min_depth(1)
+        .into_iter();
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let entry_path = entry.path();
+        if entry_path.extension().is_some() && key == &key_from_filename(entry_path)? {
+            return Ok(entry_path.to_path_buf());
+        }
+    }
+    Err(anyhow!("article with key {} not found", key))
+}
+
+// Reads the key of the article with id from the id index
+fn read_key(id_root: &Path, id: &str) -> Result<Option<Ulid>> {
+    let path = match lookup_dir_trie(id_root, id)? {
+        Some(path) => path.join("key.txt"),
+        None => return Ok(None),
+    };
+    // Intermediate nodes in the trie don't hold an article
+    match fs::read_to_string(path) {
+        Ok(key) => Ok(Some(key.parse()?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl Local {
+    // The articles matching query, read as they are iterated
+    fn iterate(&self, query: &Query) -> Result<LocalIterator> {
+        let lock = DirLock::shared(&self.path)?;
+        let mut query = query.clone();
+        for filter in query.links.iter_mut() {
+            filter.ids = self.linked_names(&filter.ids)?;
+        }
+        let linked = match query.links.first() {
+            Some(filter) if query.id.is_none() => Some(self.linked_keys(filter)?.into_iter()),
+            _ => None,
+        };
+        Ok(LocalIterator {
+            _lock: lock,
+            path: self.path.clone(),
+            query,
+            linked,
+            articles_walker: WalkDir::new(self.path.join("articles"))
+                .sort_by_file_name()
+                .min_depth(1)
+                .into_iter(),
+            id_searched: false,
+            fold_case: self.fold_case,
+        })
+    }
+
+    // Counts the articles matching query. Unlike search, an article that
+    // can't be read is an error rather than a panic, for checking data that
+    // isn't trusted yet.
+    pub fn count(&self, query: &Query) -> Result<usize> {
+        let mut articles = self.iterate(query)?;
+        let mut count = 0;
+        while articles.try_next()?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Removes every index entry of an article, its content is left in place
+    // as other articles may share it
+    fn remove_entries(&self, article: &Article) -> Result<()> {
+        let key = article.key.to_string();
+        let created: DateTime<Utc> = article.timestamp().parse()?;
+        remove_from_dir_trie(
+            &self.path.join("articles"),
+            &datetime_to_filename(&created),
+            &[&key, &format!("{}.html.hbs", key)],
+        )?;
+        remove_from_dir_trie(&self.path.join("index/key"), &key, &["meta.yaml"])?;
+
+        let id_root = self.path.join("index/id");
+        for id in self.ids(article) {
+            if read_key(&id_root, &id)? == Some(article.key) {
+                remove_from_dir_trie(&id_root, &id, &["key.txt"])?;
+            }
+        }
+        for tag in article.tags.iter() {
+            remove_from_dir_trie(&self.path.join("index/tags"), tag, &[&key])?;
+        }
+        for (kind, targets) in article.links.iter() {
+            for target in targets {
+                let id = id_key(target, self.fold_case);
+                remove_from_dir_trie(&self.links_root(*kind), &id, &[&key])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Generates an id from the title of an article that is not taken by any
+    // other article, numbering it from 2 when it is. Titles without a letter
+    // or digit in them give untitled.
+    fn unique_slug(&self, article: &Article) -> Result<String> {
+        let mut slug = slug_from_title(&article.title);
+        if slug.is_empty() {
+            slug = UNTITLED_SLUG.to_string();
+        }
+        let id_root = self.path.join("index/id");
+        for n in 1.. {
+            let id = match n {
+                1 => slug.clone(),
+                n => format!("{}-{}", slug, n),
+            };
+            match read_key(&id_root, &id_key(&id, self.fold_case))? {
+                Some(key) if key != article.key => continue,
+                _ => return Ok(id),
+            }
+        }
+        unreachable!()
+    }
+
+    // Stores body under its hash unless identical content is already stored
+    fn store_content(&self, hash: &str, body: &[u8]) -> Result<PathBuf> {
+        let path = content_path(&self.path, hash);
+        if path.is_file() {
+            return Ok(path);
+        }
+        let dir = path.parent().unwrap();
+        create_dir_all(dir)?;
+        let staging = dir.join(format!(".{}", Ulid::generate()));
+        fs::write(&staging, body)?;
+        rename(&staging, &path)?;
+        Ok(path)
+    }
+}
+
 impl Index for Local {
-    // update adds a new entry into the index, returning the location where
-    // the article is to be stored
+    // update adds a new entry into the index, or replaces the entry with the
+    // same key, returning the location where the article is to be stored
     fn update(&mut self, article: &Article) -> Result<Box<dyn Entry>> {
+        let mut errors = ValidationErrors::default();
+        if let Err(message) = validate_id(&article.id) {
+            errors.add("id", message);
+        }
+        for alias in article.aliases.iter() {
+            if let Err(message) = validate_id(alias) {
+                errors.add("aliases", format!("{} {}", alias, message));
+            }
+        }
+        errors.into_result()?;
+        // Schemas are looked up through search, so before taking the lock
+        schema::validate(self, article)?;
+
+        let _lock = DirLock::exclusive(&self.path)?;
         let now: DateTime<Utc> = article.timestamp().parse().unwrap();
 
-        // First store the raw article body as a handlebars template
+        // An article that changes id keeps its old id as an alias, so links
+        // to it keep working
+        let mut article = article.clone();
+        let previous = read_meta(&self.path, &article.key)?;
+        if article.id.is_empty() {
+            article.id = match previous {
+                Some(ref previous) if !previous.id.is_empty() => previous.id.clone(),
+                _ => self.unique_slug(&article)?,
+            };
+        }
+        if let Some(ref previous) = previous {
+            if !previous.id.is_empty() && previous.id != article.id {
+                article.aliases.insert(previous.id.clone());
+            }
+        }
+        article.aliases.remove(&article.id);
+
+        // Ids and aliases are unique, though an article can of course keep
+        // its own
+        let id_root = self.path.join("index/id");
+        let ids: Vec<String> = self.ids(&article).collect();
+        for (id, name) in ids.iter().zip(self.names(&article)) {
+            match read_key(&id_root, id)? {
+                Some(key) if key != article.key => {
+                    return Err(Error::DuplicateId(name.clone()).into());
+                }
+                _ => {}
+            }
+        }
+        if let Some(ref previous) = previous {
+            self.remove_entries(previous)?;
+        }
+
+        // First store the raw article body, addressed by its content so that
+        // identical bodies are only stored once
+        article.hash = content_hash(article.body.as_bytes());
+        let body_path = self.store_content(&article.hash, article.body.as_bytes())?;
+
+        // The articles trie orders article keys by creation time
         let key = article.key.to_string();
         let article_root = self.path.join("articles");
         create_dir_all(&article_root)?;
-        let path = update_dir_trie(&article_root, Path::new(&datetime_to_filename(&now)))?;
-
-        let article_path = path.join(format!("{}.html.hbs", key));
-        let mut article_file = File::create(&article_path)?;
-        article_file.write_all(article.body.as_bytes())?;
+        let path = node_in_dir_trie(&article_root, &datetime_to_filename(&now))?;
+        File::create(path.join(&key))?;
 
         // We store meta data in the key index, for fast lookup
         let key_root = self.path.join("index/key");
         create_dir_all(&key_root)?;
-        let path = update_dir_trie(&key_root, Path::new(&key))?;
+        let path = node_in_dir_trie(&key_root, &key)?;
 
-        let mut key_index_file = File::create(&path.join("meta.yaml"))?;
+        let mut key_index_file = File::create(path.join("meta.yaml"))?;
         key_index_file.write_all(serde_yaml::to_string(&article)?.as_bytes())?;
 
         // All other indexes could be a symlink to the meta data, or the article
-        let id_root = self.path.join("index/id");
         create_dir_all(&id_root)?;
-        let path = update_dir_trie(&id_root, Path::new(&article.id))?;
-
-        let mut key_index_file = File::create(&path.join("key.txt"))?;
-        key_index_file.write_all(key.as_bytes())?;
+        for id in ids.iter() {
+            let path = node_in_dir_trie(&id_root, id)?;
+            let mut key_index_file = File::create(path.join("key.txt"))?;
+            key_index_file.write_all(key.as_bytes())?;
+        }
 
         for tag in article.tags.iter() {
             let tag_root = self.path.join("index/tags");
             create_dir_all(&tag_root)?;
-            let path = update_dir_trie(&tag_root, Path::new(&tag))?;
+            let path = node_in_dir_trie(&tag_root, tag)?;
+            File::create(path.join(&key))?;
+        }
 
-            let mut tag_index_file = File::create(&path.join("tag"))?;
-            tag_index_file.write_all(tag.as_bytes())?;
+        // Links are also indexed by the id they point to, so the articles
+        // linking to one are found without reading every article
+        for (kind, targets) in article.links.iter() {
+            let links_root = self.links_root(*kind);
+            create_dir_all(&links_root)?;
+            for target in targets {
+                let path = node_in_dir_trie(&links_root, &id_key(target, self.fold_case))?;
+                File::create(path.join(&key))?;
+            }
         }
 
+        let kind = match previous {
+            Some(_) => ChangeKind::Updated,
+            None => ChangeKind::Created,
+        };
+        self.events.append(kind, &article, self.clock.now())?;
+
         Ok(Box::new(LocalEntry {
-            path,
-            article: article.clone(),
+            path: body_path,
+            article,
         }))
     }
 
+    fn remove(&mut self, key: &Ulid) -> Result<()> {
+        let _lock = DirLock::exclusive(&self.path)?;
+        let article = read_meta(&self.path, key)?.ok_or(Error::ArticleNotFound)?;
+        self.remove_entries(&article)?;
+        self.events
+            .append(ChangeKind::Removed, &article, self.clock.now())?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: &Ulid) -> Result<Option<Box<dyn Entry>>> {
+        let _lock = DirLock::shared(&self.path)?;
+        let entry = read_entry(&self.path, key)?;
+        Ok(entry.map(|entry| Box::new(entry) as Box<dyn Entry>))
+    }
+
     // search returns an iterator that returns all articles that match the supplied query
     fn search(&mut self, query: &Query) -> Result<Box<dyn Iterator<Item = Box<dyn Entry>>>> {
-        Ok(Box::new(LocalIterator {
-            path: self.path.clone(),
-            query: query.clone(),
-            articles_walker: WalkDir::new(self.path.join("articles"))
-                .sort_by_file_name()
-                .min_depth(1)
-                .into_iter(),
-            id_walker: WalkDir::new(self.path.join("index/id"))
-                .sort_by_file_name()
-                .min_depth(1)
-                .into_iter(),
-        }))
+        Ok(Box::new(self.iterate(query)?))
     }
-}
 
-fn move_contents(from: &Path, to: &Path) -> std::io::Result<()> {
-    for entry in WalkDir::new(from).min_depth(1).max_depth(1).into_iter() {
-        let entry = entry?;
-        rename(
-            entry.path(),
-            to.join(entry.path().strip_prefix(from).unwrap()),
-        )?;
+    fn content(&mut self, hash: &str) -> Result<Box<dyn std::io::Read>> {
+        let _lock = DirLock::shared(&self.path)?;
+        if hash.len() < 2 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::ArticleNotFound.into());
+        }
+        match File::open(content_path(&self.path, hash)) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::ArticleNotFound.into()),
+            Err(e) => Err(e.into()),
+        }
     }
-    Ok(())
+
+    fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        self.events.subscribe()
+    }
+
+    fn changes(&mut self, after: u64) -> Result<Vec<ChangeEvent>> {
+        let _lock = DirLock::shared(&self.path)?;
+        self.events.since(after)
+    }
+}
+
+// Splits are staged in a hidden directory beside the trie rather than in it,
+// as any name, even one starting with a dot, can be a node of a trie
+fn split_staging(trie: &Path) -> PathBuf {
+    let name = trie.file_name().unwrap_or_default().to_string_lossy();
+    trie.with_file_name(format!(".{}-split-{}", name, Ulid::generate()))
+}
+
+// Splits the trie node at from into prefix/suffix. The node is first moved
+// aside into the staging directory which is then renamed into place, so a
+// reader never observes a half moved node, only one that is briefly missing.
+fn split_node(
+    trie: &Path,
+    root: &Path,
+    from: &Path,
+    prefix: &str,
+    suffix: &str,
+) -> Result<PathBuf> {
+    let staging = split_staging(trie);
+    create_dir_all(&staging)?;
+    rename(from, staging.join(suffix))?;
+    let new_root = root.join(prefix);
+    rename(&staging, &new_root)?;
+    Ok(new_root)
 }
 
 fn update_dir_trie(root: &Path, location: &Path) -> Result<PathBuf> {
+    insert_into_dir_trie(root, root, location)
+}
+
+// Adds location below the node root of the trie rooted at trie
+fn insert_into_dir_trie(trie: &Path, root: &Path, location: &Path) -> Result<PathBuf> {
     eprintln!("update_dir_trie({:?}, {:?})", &root, &location);
     for entry in WalkDir::new(root)
         .sort_by_file_name()
@@ -306,34 +669,33 @@ fn update_dir_trie(root: &Path, location: &Path) -> Result<PathBuf> {
         .into_iter()
     {
         let entry = entry?;
+        // Only nodes are split, never the files an article keeps in a node
+        if !entry.file_type().is_dir() {
+            continue;
+        }
 
         match common_prefix(
             entry.file_name().to_str().unwrap(),
             location.to_str().unwrap(),
         ) {
-            (prefix, suffix, remainder) if prefix.is_empty() => {
+            ("", suffix, remainder) => {
                 assert!(!suffix.is_empty());
                 assert!(!remainder.is_empty());
                 continue;
             }
             (prefix, suffix, remainder) if !suffix.is_empty() => {
-                let new_root = root.join(Path::new(prefix));
-                let new_path = new_root.join(suffix);
-                create_dir_all(&new_path)?;
-                move_contents(entry.path(), &new_path)?;
-                remove_dir(entry.path())?;
+                let new_root = split_node(trie, root, entry.path(), prefix, suffix)?;
                 if !remainder.is_empty() {
-                    return update_dir_trie(&new_root, Path::new(remainder));
+                    return insert_into_dir_trie(trie, &new_root, Path::new(remainder));
                 }
                 return Ok(new_root);
             }
             (_, _, remainder) if !remainder.is_empty() => {
-                return update_dir_trie(entry.path(), Path::new(remainder));
+                return insert_into_dir_trie(trie, entry.path(), Path::new(remainder));
             }
-            (prefix, suffix, remainder) if suffix.is_empty() && remainder.is_empty() => {
+            (prefix, _, _) => {
                 bail!("entry already exists in trie: {}", prefix);
             }
-            m => unreachable!("attempted to store empty limb in trie {:?}", m),
         }
     }
     let new_path = root.join(location);
@@ -341,8 +703,90 @@ fn update_dir_trie(root: &Path, location: &Path) -> Result<PathBuf> {
     Ok(new_path)
 }
 
+// Returns the node for location, adding it to the trie when missing
+fn node_in_dir_trie(root: &Path, location: &str) -> Result<PathBuf> {
+    match lookup_dir_trie(root, location)? {
+        Some(node) => Ok(node),
+        None => update_dir_trie(root, Path::new(location)),
+    }
+}
+
+// Removes files from the node at location, then prunes the node and any of
+// its parents that were left empty
+fn remove_from_dir_trie(root: &Path, location: &str, files: &[&str]) -> Result<()> {
+    let node = match lookup_dir_trie(root, location)? {
+        Some(node) => node,
+        None => return Ok(()),
+    };
+    for file in files {
+        match fs::remove_file(node.join(file)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    let mut dir = node.as_path();
+    while dir != root && fs::remove_dir(dir).is_ok() {
+        dir = dir.parent().unwrap();
+    }
+    Ok(())
+}
+
+// Finds the node for location in a trie built by update_dir_trie. Another
+// process may split a node while we descend, in which case the lookup is
+// restarted from the root.
+fn lookup_dir_trie(root: &Path, location: &str) -> Result<Option<PathBuf>> {
+    if !root.is_dir() {
+        return Ok(None);
+    }
+    for _ in 0..TRIE_LOOKUP_ATTEMPTS {
+        match descend_dir_trie(root, location) {
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            result => return result.map_err(anyhow::Error::from),
+        }
+    }
+    bail!("trie under {:?} kept changing during lookup", root)
+}
+
+fn descend_dir_trie(root: &Path, location: &str) -> std::io::Result<Option<PathBuf>> {
+    let mut dir = root.to_path_buf();
+    let mut search = location;
+    'descend: loop {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            match common_prefix(name, search) {
+                ("", _, _) => continue,
+                (_, "", "") => return Ok(Some(entry.path())),
+                (_, "", remainder) => {
+                    dir = entry.path();
+                    search = remainder;
+                    continue 'descend;
+                }
+                _ => return Ok(None),
+            }
+        }
+        return Ok(None);
+    }
+}
+
+// Splits a and b at the end of their longest common prefix. The split is a
+// byte offset, only ever taken at a character boundary, which is the same in
+// both strings as the prefix is identical.
 fn common_prefix<'a, 'b>(a: &'a str, b: &'b str) -> (&'b str, &'a str, &'b str) {
-    let at = a.chars().zip(b.chars()).take_while(|(x, y)| x == y).count();
+    let at = a
+        .char_indices()
+        .zip(b.chars())
+        .take_while(|((_, x), y)| x == y)
+        .last()
+        .map(|((i, x), _)| i + x.len_utf8())
+        .unwrap_or(0);
     (
         b.get(..at).unwrap(),
         a.get(at..).unwrap(),
@@ -379,6 +823,172 @@ mod tests {
         assert_eq!(common_prefix("a", "a"), ("a", "", ""));
         assert_eq!(common_prefix("aa", "aa"), ("aa", "", ""));
         assert_eq!(common_prefix("aab", "aac"), ("aa", "b", "c"));
+        assert_eq!(common_prefix("café", "cafè"), ("caf", "é", "è"));
+        assert_eq!(common_prefix("日本", "日本語"), ("日本", "", "語"));
+        assert_eq!(common_prefix("日本", "日曜"), ("日", "本", "曜"));
+        assert_eq!(common_prefix("é", "e"), ("", "é", "e"));
+    }
+
+    #[test]
+    fn test_update_dir_trie_multibyte() {
+        let temp = TempDir::new("update_dir_trie_unicode_test").unwrap();
+        let root = temp.path().to_owned();
+
+        update_dir_trie(&root, Path::new("café")).unwrap();
+        let out = update_dir_trie(&root, Path::new("cafè")).unwrap();
+        assert!(out.ends_with("caf/è"));
+        assert_eq!(enumerate_dirs(&root), ["caf", "caf/è", "caf/é"]);
+
+        update_dir_trie(&root, Path::new("日本語")).unwrap();
+        let out = update_dir_trie(&root, Path::new("日本")).unwrap();
+        assert!(out.ends_with("日本"));
+        let out = update_dir_trie(&root, Path::new("日曜")).unwrap();
+        assert!(out.ends_with("日/曜"));
+        assert_eq!(
+            enumerate_dirs(&root),
+            ["caf", "caf/è", "caf/é", "日", "日/曜", "日/本", "日/本/語"]
+        );
+
+        assert!(lookup_dir_trie(&root, "日本語")
+            .unwrap()
+            .unwrap()
+            .ends_with("日/本/語"));
+        assert!(lookup_dir_trie(&root, "café")
+            .unwrap()
+            .unwrap()
+            .ends_with("caf/é"));
+        assert_eq!(lookup_dir_trie(&root, "cafe").unwrap(), None);
+    }
+
+    #[test]
+    fn test_id_key() {
+        // Decomposed e followed by a combining acute accent
+        assert_eq!(id_key("cafe\u{301}", false), "café");
+        assert_eq!(id_key("Café", false), "Café");
+        assert_eq!(id_key("Café", true), "café");
+        assert_eq!(id_key("ÆON", true), "æon");
+        assert_eq!(id_key("blog/2023/100%", false), "blog%2F2023%2F100%25");
+        assert_eq!(id_key("blog%2F2023", false), "blog%252F2023");
+        assert_eq!(id_key("schema.product", false), "schema%2Eproduct");
+    }
+
+    #[test]
+    fn test_lookup_dir_trie() {
+        let temp = TempDir::new("lookup_dir_trie_test").unwrap();
+        let root = temp.path().to_owned();
+        assert_eq!(lookup_dir_trie(&root.join("missing"), "a").unwrap(), None);
+
+        for location in ["caa", "ca", "cab", "d"] {
+            update_dir_trie(&root, Path::new(location)).unwrap();
+        }
+        assert!(lookup_dir_trie(&root, "caa")
+            .unwrap()
+            .unwrap()
+            .ends_with("ca/a"));
+        assert!(lookup_dir_trie(&root, "cab")
+            .unwrap()
+            .unwrap()
+            .ends_with("ca/b"));
+        assert!(lookup_dir_trie(&root, "ca")
+            .unwrap()
+            .unwrap()
+            .ends_with("ca"));
+        assert!(lookup_dir_trie(&root, "d").unwrap().unwrap().ends_with("d"));
+        assert_eq!(lookup_dir_trie(&root, "cac").unwrap(), None);
+        assert_eq!(lookup_dir_trie(&root, "e").unwrap(), None);
+    }
+
+    #[test]
+    fn test_split_leaves_no_staging() {
+        let temp = TempDir::new("split_node_test").unwrap();
+        let root = temp.path().join("trie");
+        create_dir_all(&root).unwrap();
+        let leaf = update_dir_trie(&root, Path::new("caa")).unwrap();
+        File::create(leaf.join("key.txt")).unwrap();
+
+        update_dir_trie(&root, Path::new("ca")).unwrap();
+        assert_eq!(
+            enumerate_dirs(temp.path()),
+            ["trie", "trie/ca", "trie/ca/a", "trie/ca/a/key.txt"]
+        );
+    }
+
+    #[test]
+    fn test_dir_trie_dotted_nodes() {
+        let temp = TempDir::new("dotted_trie_test").unwrap();
+        let root = temp.path().join("trie");
+        create_dir_all(&root).unwrap();
+        for location in ["v1", "v1.2"] {
+            let node = node_in_dir_trie(&root, location).unwrap();
+            File::create(node.join("key.txt")).unwrap();
+        }
+        // A node may start with a dot, and is found like any other
+        let dotted = lookup_dir_trie(&root, "v1.2").unwrap().unwrap();
+        assert!(dotted.ends_with("v1/.2"));
+        assert_eq!(node_in_dir_trie(&root, "v1.2").unwrap(), dotted);
+        assert!(update_dir_trie(&root, Path::new("v1.2")).is_err());
+    }
+
+    #[test]
+    fn test_update_dir_trie_skips_files() {
+        let temp = TempDir::new("update_dir_trie_files_test").unwrap();
+        let root = temp.path().to_owned();
+        let boo = update_dir_trie(&root, Path::new("boo")).unwrap();
+        File::create(boo.join("key.txt")).unwrap();
+
+        let book = update_dir_trie(&root, Path::new("book")).unwrap();
+        assert!(book.ends_with("boo/k"));
+        assert_eq!(enumerate_dirs(&root), ["boo", "boo/k", "boo/key.txt"]);
+        assert_eq!(lookup_dir_trie(&root, "boo").unwrap(), Some(boo));
+    }
+
+    #[test]
+    fn test_remove_from_dir_trie() {
+        let temp = TempDir::new("remove_dir_trie_test").unwrap();
+        let root = temp.path().to_owned();
+        for location in ["caa", "cab", "d"] {
+            let node = node_in_dir_trie(&root, location).unwrap();
+            File::create(node.join("key.txt")).unwrap();
+        }
+        // Existing and intermediate nodes are reused rather than rejected
+        assert!(node_in_dir_trie(&root, "ca").unwrap().ends_with("ca"));
+        assert!(node_in_dir_trie(&root, "cab").unwrap().ends_with("ca/b"));
+
+        remove_from_dir_trie(&root, "caa", &["key.txt"]).unwrap();
+        assert_eq!(
+            enumerate_dirs(&root),
+            ["ca", "ca/b", "ca/b/key.txt", "d", "d/key.txt"]
+        );
+        remove_from_dir_trie(&root, "cab", &["key.txt"]).unwrap();
+        assert_eq!(enumerate_dirs(&root), ["d", "d/key.txt"]);
+        remove_from_dir_trie(&root, "missing", &["key.txt"]).unwrap();
+        assert_eq!(enumerate_dirs(&root), ["d", "d/key.txt"]);
+    }
+
+    #[test]
+    fn test_count_unreadable_article() {
+        let temp = TempDir::new("count_test").unwrap();
+        let local = Local::new(temp.path()).unwrap();
+        assert_eq!(local.count(&Query::default()).unwrap(), 0);
+
+        // An article without metadata, as in a crafted archive
+        let node = temp.path().join("articles/20240101000000.000000000");
+        create_dir_all(&node).unwrap();
+        File::create(node.join(Ulid::generate().to_string())).unwrap();
+        assert!(local.count(&Query::default()).is_err());
+    }
+
+    #[test]
+    fn test_dir_lock() {
+        let temp = TempDir::new("dir_lock_test").unwrap();
+        let shared = DirLock::shared(temp.path()).unwrap();
+        let other = DirLock::open(temp.path()).unwrap();
+        assert!(other.try_lock_shared().is_ok());
+        other.unlock().unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+
+        drop(shared);
+        assert!(other.try_lock_exclusive().is_ok());
     }
 
     #[test]
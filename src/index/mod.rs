@@ -1,21 +1,51 @@
+pub mod events;
 pub mod local;
+// rdb is an unfinished relational backend, only built with --features rdb
+#[cfg(feature = "rdb")]
 pub mod rdb;
 
+use std::io::Read;
+use std::sync::mpsc::Receiver;
+
 use anyhow::Result;
+use rusty_ulid::Ulid;
 use thiserror::Error;
 
 use crate::articles::Article;
+use crate::index::events::ChangeEvent;
 use crate::query::Query;
 
+// Entry is a single search result. article() only holds metadata, the body
+// is read lazily through body() so listings don't pay for content they skip.
 pub trait Entry {
     fn article(&self) -> Article;
-    fn body(&self) -> Result<Box<dyn std::io::Read>>;
+    fn body(&self) -> Result<Box<dyn Read>>;
+
+    fn body_string(&self) -> Result<String> {
+        let mut buffer = String::new();
+        self.body()?.read_to_string(&mut buffer)?;
+        Ok(buffer)
+    }
 }
 
 pub trait Index: Send + Sync {
     fn update(&mut self, article: &Article) -> Result<Box<dyn Entry>>;
+    fn remove(&mut self, key: &Ulid) -> Result<()>;
     fn search(&mut self, query: &Query) -> Result<Box<dyn Iterator<Item = Box<dyn Entry>>>>;
 
+    // get looks an article up by its key, which never changes
+    fn get(&mut self, key: &Ulid) -> Result<Option<Box<dyn Entry>>>;
+
+    // content reads a body directly by the hash of its content
+    fn content(&mut self, hash: &str) -> Result<Box<dyn Read>>;
+
+    // subscribe returns a receiver for every change made through this index
+    fn subscribe(&mut self) -> Receiver<ChangeEvent>;
+
+    // changes returns the persisted changes made after the sequence after,
+    // letting consumers catch up after a restart
+    fn changes(&mut self, after: u64) -> Result<Vec<ChangeEvent>>;
+
     fn first(&mut self, query: &Query) -> Result<Box<dyn Entry>> {
         self.search(query)?
             .next()
@@ -27,16 +57,19 @@ pub trait Index: Send + Sync {
 pub enum Error {
     #[error("no articles found that match that query")]
     ArticleNotFound,
-    #[error("internal server error")]
-    InternalError,
+    #[error("an article with id {0} already exists")]
+    DuplicateId(String),
 }
 
 #[cfg(test)]
 mod tests {
     use std::convert::TryInto;
 
+    use crate::index::events::ChangeKind;
     use crate::index::local::Local;
     use crate::index::*;
+    use crate::query;
+    use crate::validation::ValidationErrors;
     use crate::NewArticleRequest;
     use tempdir::TempDir;
 
@@ -62,6 +95,7 @@ mod tests {
         assert_eq!(1, result.len());
         dbg!(&result[0]);
         assert_eq!(result[0].id, "main");
+        assert!(!result[0].hash.is_empty());
 
         // let result: Vec<Article> = index
         //     .search(&"tag1".try_into().unwrap())
@@ -88,15 +122,83 @@ mod tests {
 
         index.update(&article).unwrap();
 
-        let result: Vec<Article> = index
-            .search(query::ALL)
+        let result: Vec<Box<dyn Entry>> = index.search(query::ALL).unwrap().collect();
+        assert_eq!(1, result.len());
+        let article = result[0].article();
+        dbg!(&article);
+        assert_eq!(article.id, "main");
+        // Bodies are only read on demand
+        assert!(article.body.is_empty());
+        assert_eq!(result[0].body_string().unwrap(), "body text");
+
+        let entry = index.first(&"@main".try_into().unwrap()).unwrap();
+        assert_eq!(entry.body_string().unwrap(), "body text");
+
+        let entry = index.get(&article.key).unwrap().unwrap();
+        assert_eq!(entry.article().id, "main");
+        assert_eq!(entry.body_string().unwrap(), "body text");
+        assert!(index.get(&Ulid::generate()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_index_content_addressed() {
+        let dir = TempDir::new("index_content_test").unwrap();
+        let mut index = Local::new(dir.path()).unwrap();
+
+        let first = index
+            .update(&Article::new(&NewArticleRequest {
+                id: "first".to_string(),
+                body: "same body".to_string(),
+                ..Default::default()
+            }))
             .unwrap()
-            .map(|e| e.article())
+            .article();
+        let second = index
+            .update(&Article::new(&NewArticleRequest {
+                id: "second".to_string(),
+                body: "same body".to_string(),
+                ..Default::default()
+            }))
+            .unwrap()
+            .article();
+        index
+            .update(&Article::new(&NewArticleRequest {
+                id: "third".to_string(),
+                body: "other body".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        // Identical bodies are stored once
+        assert_eq!(first.hash, second.hash);
+        let stored = walkdir::WalkDir::new(dir.path().join("content"))
+            .into_iter()
+            .filter(|e| e.as_ref().unwrap().file_type().is_file())
+            .count();
+        assert_eq!(2, stored);
+
+        let mut body = String::new();
+        index
+            .content(&first.hash)
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "same body");
+        assert!(index.content("0000").is_err());
+
+        let query: Query = format!("hash:{}", &first.hash).as_str().try_into().unwrap();
+        let ids: Vec<String> = index
+            .search(&query)
+            .unwrap()
+            .map(|e| e.article().id)
             .collect();
-        assert_eq!(1, result.len());
-        dbg!(&result[0]);
-        assert_eq!(result[0].id, "main");
-        assert!(!result[0].body.is_empty());
+        assert_eq!(ids, ["first", "second"]);
+
+        let query: Query = format!("@second hash:{}", &first.hash[..8])
+            .as_str()
+            .try_into()
+            .unwrap();
+        assert_eq!(index.first(&query).unwrap().article().id, "second");
 
         // let result: Vec<Article> = index
         //     .search(&"tag1".try_into().unwrap())
@@ -107,4 +209,258 @@ mod tests {
         // dbg!(&result[0]);
         // assert_eq!(1, result[0].tags.len());
     }
+
+    #[test]
+    fn test_index_update_and_remove() {
+        let dir = TempDir::new("index_update_test").unwrap();
+        let mut index = Local::new(dir.path()).unwrap();
+        let changes = index.subscribe();
+
+        let mut article = Article::new(&NewArticleRequest {
+            id: "post".to_string(),
+            body: "first draft".to_string(),
+            tags: "news blog".to_string(),
+            ..Default::default()
+        });
+        index.update(&article).unwrap();
+        index
+            .update(&Article::new(&NewArticleRequest {
+                id: "other".to_string(),
+                tags: "news".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        // Ids must be unique
+        let duplicate = index.update(&Article::new(&NewArticleRequest {
+            id: "post".to_string(),
+            ..Default::default()
+        }));
+        assert_eq!(
+            duplicate.err().unwrap().downcast_ref::<Error>(),
+            Some(&Error::DuplicateId("post".to_string()))
+        );
+
+        // Updating by key replaces the old id and tags, the old id is kept as
+        // an alias
+        article.id = "renamed".to_string();
+        article.body = "second draft".to_string();
+        article.tags.remove("news");
+        index.update(&article).unwrap();
+        assert_eq!(2, index.search(query::ALL).unwrap().count());
+        let entry = index.first(&"@post".try_into().unwrap()).unwrap();
+        assert_eq!(entry.article().id, "renamed");
+        let entry = index.first(&"@renamed".try_into().unwrap()).unwrap();
+        assert_eq!(entry.body_string().unwrap(), "second draft");
+        let news: Vec<String> = index
+            .search(&"news".try_into().unwrap())
+            .unwrap()
+            .map(|e| e.article().id)
+            .collect();
+        assert_eq!(news, ["other"]);
+
+        index.remove(&article.key).unwrap();
+        assert_eq!(1, index.search(query::ALL).unwrap().count());
+        assert!(index
+            .search(&"@renamed".try_into().unwrap())
+            .unwrap()
+            .next()
+            .is_none());
+        assert!(index.remove(&article.key).is_err());
+
+        let kinds: Vec<ChangeKind> = changes.try_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                ChangeKind::Created,
+                ChangeKind::Created,
+                ChangeKind::Updated,
+                ChangeKind::Removed
+            ]
+        );
+        let persisted = index.changes(2).unwrap();
+        assert_eq!(persisted.len(), 2);
+        assert_eq!(persisted[0].id, "renamed");
+    }
+
+    #[test]
+    fn test_index_hierarchical_ids() {
+        let dir = TempDir::new("index_hierarchical_test").unwrap();
+        let mut index = Local::new(dir.path()).unwrap();
+
+        for id in ["blog", "blog/2023", "blog/2023/hello", "blog/2024/hello"] {
+            index
+                .update(&Article::new(&NewArticleRequest {
+                    id: id.to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        // Each id is a single entry in the id trie, not a directory per part
+        assert!(!dir.path().join("index/id/blog/2023").exists());
+
+        let entry = index
+            .first(&"@blog/2023/hello".try_into().unwrap())
+            .unwrap();
+        assert_eq!(entry.article().id, "blog/2023/hello");
+
+        let ids = |index: &mut Local, query: &str| {
+            let mut ids: Vec<String> = index
+                .search(&query.try_into().unwrap())
+                .unwrap()
+                .map(|e| e.article().id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(&mut index, "@blog/*"), ["blog/2023"]);
+        assert_eq!(
+            ids(&mut index, "@blog/**"),
+            ["blog/2023", "blog/2023/hello", "blog/2024/hello"]
+        );
+
+        let traversal = index.update(&Article::new(&NewArticleRequest {
+            id: "blog/../../etc".to_string(),
+            ..Default::default()
+        }));
+        let errors = traversal.err().unwrap();
+        let errors = errors.downcast_ref::<ValidationErrors>().unwrap();
+        assert!(errors.fields.contains_key("id"));
+    }
+
+    #[test]
+    fn test_index_links() {
+        let dir = TempDir::new("index_links_test").unwrap();
+        let mut index = Local::new(dir.path()).unwrap();
+
+        let mut docs = Article::new(&NewArticleRequest {
+            id: "docs".to_string(),
+            ..Default::default()
+        });
+        index.update(&docs).unwrap();
+        let mut install = Article::new(&NewArticleRequest {
+            id: "install".to_string(),
+            links: "parent:docs next:usage".to_string(),
+            ..Default::default()
+        });
+        index.update(&install).unwrap();
+        for (id, links) in [
+            ("usage", "parent:docs prev:install"),
+            ("faq", "related:docs"),
+        ] {
+            index
+                .update(&Article::new(&NewArticleRequest {
+                    id: id.to_string(),
+                    links: links.to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        assert!(dir.path().join("index/links/parent/docs").is_dir());
+
+        let ids = |index: &mut Local, query: &str| -> Vec<String> {
+            index
+                .search(&query.try_into().unwrap())
+                .unwrap()
+                .map(|e| e.article().id)
+                .collect()
+        };
+        assert_eq!(ids(&mut index, "parent=@docs"), ["install", "usage"]);
+        assert_eq!(ids(&mut index, "links=@docs"), ["install", "usage", "faq"]);
+        assert_eq!(ids(&mut index, "links=@install"), ["usage"]);
+        assert!(ids(&mut index, "parent=@usage").is_empty());
+
+        // Links to the old id of a renamed article still count
+        docs.id = "manual".to_string();
+        index.update(&docs).unwrap();
+        assert_eq!(ids(&mut index, "parent=@manual"), ["install", "usage"]);
+
+        // Changed and removed links leave the index
+        install.links.clear();
+        index.update(&install).unwrap();
+        assert_eq!(ids(&mut index, "parent=@manual"), ["usage"]);
+        let usage = index.first(&"@usage".try_into().unwrap()).unwrap();
+        index.remove(&usage.article().key).unwrap();
+        assert!(ids(&mut index, "parent=@docs").is_empty());
+        assert!(!dir.path().join("index/links/parent/docs").exists());
+    }
+
+    #[test]
+    fn test_index_generates_ids() {
+        let dir = TempDir::new("index_slug_test").unwrap();
+        let mut index = Local::new(dir.path()).unwrap();
+
+        let mut ids = vec![];
+        for _ in 0..3 {
+            let entry = index
+                .update(&Article::new(&NewArticleRequest {
+                    title: "My Post".to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+            ids.push(entry.article().id);
+        }
+        assert_eq!(ids, ["my-post", "my-post-2", "my-post-3"]);
+
+        // A given id is kept, and so is a generated one when saved again
+        let entry = index
+            .update(&Article::new(&NewArticleRequest {
+                id: "chosen".to_string(),
+                title: "My Post".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+        assert_eq!(entry.article().id, "chosen");
+        let mut article = index
+            .first(&"@my-post-2".try_into().unwrap())
+            .unwrap()
+            .article();
+        article.id = String::new();
+        article.title = "Renamed".to_string();
+        assert_eq!(index.update(&article).unwrap().article().id, "my-post-2");
+
+        // A title without letters or digits still gets an id
+        for expected in ["untitled", "untitled-2"] {
+            let entry = index
+                .update(&Article::new(&NewArticleRequest {
+                    title: "!!!".to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+            assert_eq!(entry.article().id, expected);
+        }
+        assert!(index.first(&"@untitled".try_into().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_index_dotted_ids() {
+        let dir = TempDir::new("index_dotted_test").unwrap();
+        let mut index = Local::new(dir.path()).unwrap();
+        let mut keys = vec![];
+        for id in ["a", "a.b", "a.c"] {
+            let entry = index
+                .update(&Article::new(&NewArticleRequest {
+                    id: id.to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+            keys.push(entry.article().key);
+        }
+        for (id, key) in ["a", "a.b", "a.c"].iter().zip(&keys) {
+            let query = format!("@{}", id);
+            let found = index.first(&query.as_str().try_into().unwrap()).unwrap();
+            assert_eq!(&found.article().key, key);
+        }
+
+        let duplicate = index.update(&Article::new(&NewArticleRequest {
+            id: "a.b".to_string(),
+            ..Default::default()
+        }));
+        assert_eq!(
+            duplicate.err().unwrap().downcast_ref::<Error>(),
+            Some(&Error::DuplicateId("a.b".to_string()))
+        );
+        let found = index.first(&"@a.b".try_into().unwrap()).unwrap();
+        assert_eq!(found.article().key, keys[1]);
+    }
 }
@@ -1,8 +1,11 @@
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use rusty_ulid::Ulid;
 
 use crate::articles::Article;
+use crate::index::events::ChangeEvent;
 use crate::index::{Entry, Index};
 use crate::query::Query;
 
@@ -63,4 +66,25 @@ impl Index for RelationalDB {
     fn search(&mut self, _query: &Query) -> Result<Box<dyn Iterator<Item = Box<dyn Entry>>>> {
         Ok(Box::new(RelationalIndexIterator { results: vec![] }))
     }
+
+    fn get(&mut self, _key: &Ulid) -> Result<Option<Box<dyn Entry>>> {
+        Ok(None)
+    }
+
+    fn remove(&mut self, _key: &Ulid) -> Result<()> {
+        Err(anyhow!("not supported by rdb"))
+    }
+
+    fn content(&mut self, _hash: &str) -> Result<Box<dyn std::io::Read>> {
+        Err(anyhow!("not supported by rdb"))
+    }
+
+    // Nothing changes here, so subscribers find the feed already closed
+    fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        mpsc::channel().1
+    }
+
+    fn changes(&mut self, _after: u64) -> Result<Vec<ChangeEvent>> {
+        Err(anyhow!("not supported by rdb"))
+    }
 }
@@ -0,0 +1,191 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError, Renderable,
+    StringOutput,
+};
+use serde_json::Value;
+
+use crate::articles::{lookup_article, normalize_id, Article};
+use crate::format::{Format, Sanitize};
+use crate::site::App;
+use crate::templates::{render_body, show_drafts};
+
+// The property naming the article that wraps an article, such as layout:site.
+// Layouts may have layouts of their own.
+pub const LAYOUT_PROPERTY: &str = "layout";
+
+// The block that whatever a wrapped body writes outside of its blocks goes
+// into, unless it defines the block itself
+pub const CONTENT_BLOCK: &str = "content";
+
+// An article with a layout is rendered first, keeping the blocks it defines
+// with {{#block "name"}}...{{/block}} rather than writing them. Its layout is
+// rendered next, writing each of those blocks where it has a block of the same
+// name and its own contents for blocks that weren't defined.
+#[derive(Default)]
+struct Frame {
+    blocks: HashMap<String, String>,
+    // Blocks defined by the body being rendered, rather than the ones inside
+    // it, which it may not fill
+    defined: HashSet<String>,
+    // Whether the body being rendered has a layout, so defines its blocks
+    wrapped: bool,
+    format: Option<Format>,
+    sanitize: Sanitize,
+}
+
+thread_local! {
+    static FRAMES: RefCell<Vec<Frame>> = const { RefCell::new(vec![]) };
+}
+
+// Blocks defined while a BlockScope is alive belong to it alone, so articles
+// embedded in a page don't fill the blocks of the page
+pub struct BlockScope;
+
+impl BlockScope {
+    pub fn start() -> Self {
+        FRAMES.with(|frames| frames.borrow_mut().push(Frame::default()));
+        BlockScope
+    }
+}
+
+impl Drop for BlockScope {
+    fn drop(&mut self) {
+        FRAMES.with(|frames| frames.borrow_mut().pop());
+    }
+}
+
+fn with_frame<T>(f: impl FnOnce(&mut Frame) -> T) -> Option<T> {
+    FRAMES.with(|frames| frames.borrow_mut().last_mut().map(f))
+}
+
+// Renders an article as a page, wrapped in its layout, the layout of that and
+// so on. Layouts are looked up like {{ article }} does, so a template file can
+// be the outermost one. Every body is rendered in the same parent context.
+pub fn render_with_layout(
+    handlebars: &Handlebars,
+    state: &App,
+    article: Option<Article>,
+    body: String,
+    parent: &Value,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let _scope = BlockScope::start();
+    let (mut article, mut body) = (article, body);
+    let mut seen = HashSet::new();
+    loop {
+        let layout = article
+            .as_ref()
+            .and_then(|article| article.properties.get(LAYOUT_PROPERTY))
+            .map(|id| normalize_id(id));
+        let layout = match layout {
+            Some(layout) => layout,
+            None => {
+                with_frame(|frame| {
+                    frame.wrapped = false;
+                    frame.defined.clear();
+                });
+                return render_body(handlebars, state, article.as_ref(), &body, parent, out);
+            }
+        };
+        if let Some(ref article) = article {
+            seen.insert(article.id.clone());
+        }
+        if !seen.insert(layout.clone()) {
+            return Err(RenderError::new(format!("layout {} wraps itself", layout)));
+        }
+
+        with_frame(|frame| {
+            frame.wrapped = true;
+            frame.defined.clear();
+            frame.format = Some(Format::of(article.as_ref()));
+            frame.sanitize = state.sanitize;
+        });
+        let mut content = StringOutput::new();
+        render_body(
+            handlebars,
+            state,
+            article.as_ref(),
+            &body,
+            parent,
+            &mut content,
+        )?;
+        let content = content.into_string()?;
+        with_frame(|frame| {
+            if !frame.defined.contains(CONTENT_BLOCK) {
+                frame.blocks.insert(CONTENT_BLOCK.to_string(), content);
+            }
+        });
+
+        let visibility = state.visibility(show_drafts());
+        let (layout_article, mut reader) = {
+            let mut index = state.index.lock().unwrap();
+            lookup_article(
+                &mut index,
+                &state.paths.templates,
+                &format!("@{}", layout),
+                visibility,
+            )
+            .map_err(|e| RenderError::new(format!("layout {}: {}", layout, e)))?
+        };
+        body = String::new();
+        reader.read_to_string(&mut body)?;
+        article = layout_article;
+    }
+}
+
+// {{#block "name"}}default{{/block}} defines a block in an article with a
+// layout, and is filled by the article it wraps in a layout. Blocks defined
+// further in win, so a layout with a layout of its own only passes them on,
+// apart from the content block it places within its own content.
+pub fn block_helper<'reg, 'rc>(
+    h: &Helper<'reg, 'rc>,
+    handlebars: &'reg Handlebars<'reg>,
+    context: &'rc Context,
+    rc: &mut RenderContext<'reg, 'rc>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let name = h
+        .param(0)
+        .and_then(|v| v.value().as_str())
+        .ok_or_else(|| RenderError::new("requires a block name"))?;
+    let (filled, wrapped) = with_frame(|frame| {
+        let filled = match frame.defined.contains(name) {
+            true => None,
+            false => frame.blocks.get(name).cloned(),
+        };
+        (filled, frame.wrapped)
+    })
+    .unwrap_or_default();
+    match filled {
+        Some(filled) if !wrapped || name == CONTENT_BLOCK => {
+            out.write(&filled)?;
+            return Ok(());
+        }
+        Some(_) => return Ok(()),
+        None => {}
+    }
+
+    let mut inner = StringOutput::new();
+    if let Some(template) = h.template() {
+        template.render(handlebars, context, rc, &mut inner)?;
+    }
+    let inner = inner.into_string()?;
+    if !wrapped {
+        out.write(&inner)?;
+        return Ok(());
+    }
+    // Blocks are converted by the format of the body defining them, as they
+    // are taken out of it before it is converted
+    with_frame(|frame| {
+        let format = frame.format.unwrap_or(Format::Html);
+        frame
+            .blocks
+            .insert(name.to_string(), format.render(&inner, frame.sanitize));
+        frame.defined.insert(name.to_string());
+    });
+    Ok(())
+}
@@ -1,42 +1,125 @@
 mod articles;
+mod auth;
+mod backup;
+mod clock;
 mod error;
+mod format;
+mod front_matter;
+mod images;
 mod index;
+mod layouts;
+mod media;
+mod pagination;
 mod query;
+mod redirects;
+mod schema;
+mod site;
 mod templates;
+mod validation;
 
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
 
-use rocket::form::Form;
-use rocket::fs::{relative, FileServer};
-use rocket::response::{status::NotFound, Redirect};
-use rocket::{get, launch, post, routes, State};
-use rocket_dyn_templates::serde::Serialize;
-use rocket_dyn_templates::Template;
+use rocket::fairing::AdHoc;
+use rocket::form::{Form, FromForm};
+use rocket::fs::{NamedFile, TempFile};
+use rocket::http::{ContentType, Header, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::content::{RawHtml, RawJson};
+use rocket::response::stream::{Event, EventStream};
+use rocket::response::{self, Redirect, Responder};
+use rocket::serde::json::Json;
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::task::spawn_blocking;
+use rocket::{catchers, get, launch, post, routes};
+use rusty_ulid::Ulid;
+use serde_derive::Serialize;
 
-use crate::articles::{Article, NewArticleRequest};
-use crate::index::{local::Local, Index};
-use crate::query::Query;
-use crate::templates::register_helpers;
+use crate::articles::{first_visible, normalize_id, validate_id, Article, NewArticleRequest};
+use crate::auth::{Admin, Preview};
+use crate::images::Transform;
+use crate::index::Entry;
+use crate::media::{Media, MediaFile};
+use crate::pagination::{find_page, PageContext, Position};
+use crate::query::{Query, Sort};
+use crate::site::{Site, Sites};
+use crate::templates::ArticleContext;
+use crate::validation::ValidationErrors;
 
-#[derive(Clone)]
-pub struct App {
-    index: Arc<Mutex<Box<dyn Index>>>,
+// Validates an untrusted request and saves the article it describes
+fn save_article(site: &Site, request: &NewArticleRequest) -> anyhow::Result<Box<dyn Entry>> {
+    request.validate()?;
+    let article = Article::new_at(request, site.clock.now(), site.timezone);
+    site.index.lock().unwrap().update(&article)
+}
+
+// Fills the editor with an article and its media
+fn edit_entry(site: &Site, ctx: &mut IndexContext, entry: &dyn Entry) -> anyhow::Result<()> {
+    let article = entry.article();
+    ctx.form = Some(NewArticleRequest::for_editing(
+        &article,
+        &entry.body_string()?,
+    ));
+    ctx.media = media::list(&site.paths.data, &article.key)?;
+    ctx.article = Some(article);
+    Ok(())
+}
+
+// Finds an article by its key, unpublished ones included
+fn find_by_key(site: &Site, key: &Ulid) -> anyhow::Result<Option<Box<dyn Entry>>> {
+    site.index.lock().unwrap().get(key)
 }
 
 #[post("/articles", data = "<article_request>")]
 fn create_article(
-    index_state: &State<Arc<App>>,
+    site: Site,
+    _admin: Admin,
     article_request: Form<NewArticleRequest>,
-) -> Result<Template, error::Error> {
+) -> Result<RawHtml<String>, error::Error> {
     let mut ctx = IndexContext::default();
-    let article = Article::new(&article_request);
-    if index_state.index.lock().unwrap().update(&article).is_err() {
-        ctx.flash = Some("Error creating article".into());
-    } else {
-        ctx.article = Some(article);
+    let article_request = article_request.into_inner();
+    match save_article(&site, &article_request) {
+        Ok(entry) => edit_entry(&site, &mut ctx, &*entry)?,
+        Err(e) => {
+            match e.downcast::<ValidationErrors>() {
+                Ok(errors) => {
+                    ctx.flash = Some("Please correct the errors below".into());
+                    ctx.errors = Some(errors);
+                }
+                Err(_) => ctx.flash = Some("Error creating article".into()),
+            }
+            ctx.form = Some(article_request);
+        }
+    }
+    site.preview("admin", &ctx)
+}
+
+// JSON counterpart of create_article, validation errors are returned per
+// field with a 422
+#[post("/api/articles", format = "json", data = "<article_request>")]
+fn api_create_article(
+    site: Site,
+    _admin: Admin,
+    article_request: Json<NewArticleRequest>,
+) -> Result<(Status, RawJson<String>), error::Error> {
+    match save_article(&site, &article_request) {
+        Ok(entry) => Ok((
+            Status::Created,
+            RawJson(serde_json::to_string(&entry.article())?),
+        )),
+        Err(e) => match e.downcast::<ValidationErrors>() {
+            Ok(errors) => Ok((
+                Status::UnprocessableEntity,
+                RawJson(serde_json::to_string(&errors)?),
+            )),
+            Err(e) => Err(e.into()),
+        },
     }
-    Ok(Template::render("admin", ctx))
 }
 
 #[get("/")]
@@ -44,23 +127,80 @@ fn redirect_to_root() -> Redirect {
     Redirect::to("/index")
 }
 
+// Unpublished articles are only served to administrators asking for a
+// ?preview
 #[get("/articles/<path..>")]
 fn serve_article(
-    index_state: &State<Arc<App>>,
+    site: Site,
+    preview: Preview,
     path: PathBuf,
-) -> Result<Template, NotFound<String>> {
+) -> Result<ArticleResponse, error::Error> {
     let query: Query = match path.to_str().unwrap().try_into() {
         Ok(v) => v,
-        _ => return Err(NotFound("".to_string())),
+        _ => return Err(not_found()),
     };
-    let ctx = IndexContext::default();
-    match index_state.index.lock().unwrap().first(&query) {
-        Ok(a) => Ok(Template::render(a.article().body, ctx)),
-        Err(_e) => {
-            // Err(e) if e == Error::ArticleNotFound => {
-            Err(NotFound("".to_string()))
-        }
-        // _ => todo!("generic error / 500"),
+    render_article(&site, &query, preview.0)
+}
+
+// Serves articles at the path of their id, so /blog/2023/hello is the article
+// @blog/2023/hello. Ranked after every other route. Aliases redirect
+// permanently to the id of their article.
+#[get("/<path..>", rank = 20)]
+fn serve_article_path(
+    site: Site,
+    preview: Preview,
+    path: PathBuf,
+) -> Result<ArticleResponse, error::Error> {
+    let id = match path.to_str() {
+        Some(id) if validate_id(id).is_ok() => normalize_id(id),
+        _ => return Err(not_found()),
+    };
+    let query = Query {
+        id: Some(id),
+        ..Default::default()
+    };
+    render_article(&site, &query, preview.0)
+}
+
+#[derive(Responder)]
+enum ArticleResponse {
+    Page(RawHtml<String>),
+    Moved(Box<Redirect>),
+}
+
+// Answered with a 404, for paths that can't name an article
+fn not_found() -> error::Error {
+    anyhow::Error::from(index::Error::ArticleNotFound).into()
+}
+
+// Renders the first article matching query. One found by an alias redirects
+// permanently to the id of the article instead.
+fn render_article(
+    site: &Site,
+    query: &Query,
+    drafts: bool,
+) -> Result<ArticleResponse, error::Error> {
+    let visibility = site.visibility(drafts);
+    let entry = first_visible(&mut **site.index.lock().unwrap(), query, visibility)?;
+    let article = entry.article();
+    if query.id.as_ref().is_some_and(|id| id != &article.id) {
+        let redirect = Redirect::moved(article.uri());
+        return Ok(ArticleResponse::Moved(Box::new(redirect)));
+    }
+    render_entry(site, entry, drafts).map(ArticleResponse::Page)
+}
+
+// Renders the stored body of an article as a template. Articles that aren't
+// found are a 404, while failing to render one is a 500.
+fn render_entry(
+    site: &Site,
+    entry: Box<dyn Entry>,
+    drafts: bool,
+) -> Result<RawHtml<String>, error::Error> {
+    let (article, body) = (entry.article(), entry.body_string()?);
+    match drafts {
+        true => site.preview_article(&article, body),
+        false => site.render_article(&article, body),
     }
 }
 
@@ -69,50 +209,589 @@ struct IndexContext {
     debug: bool,
     flash: Option<String>,
     article: Option<Article>,
+    // The values of the article form
+    form: Option<NewArticleRequest>,
+    errors: Option<ValidationErrors>,
+    // Files uploaded alongside the article being edited
+    media: Vec<Media>,
 }
 
-#[get("/articles")]
-fn serve_articles() -> Template {
-    let ctx = IndexContext::default();
-    Template::render("articles/index", ctx)
+#[derive(Serialize)]
+struct ArticlesContext<'a> {
+    // The search, in the query language
+    q: &'a str,
+    error: Option<String>,
+    page: Option<PageContext<ArticleContext<'a>>>,
 }
 
-// TODO: Authentication
-#[get("/admin")]
-fn serve_admin() -> Template {
-    let ctx = IndexContext::default();
-    Template::render("admin", ctx)
+// Lists the articles matching ?q=, newest first unless the query sorts them,
+// a page at a time
+#[get("/articles?<q>")]
+fn serve_articles(
+    site: Site,
+    preview: Preview,
+    q: Option<&str>,
+) -> Result<RawHtml<String>, error::Error> {
+    let q = q.unwrap_or_default().trim();
+    let found = Query::try_from(q)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut query| {
+            query.sort.get_or_insert_with(|| Sort {
+                field: "epoch".to_string(),
+                descending: true,
+            });
+            let position = Position::from_params(&site.request().query);
+            let mut index = site.index.lock().unwrap();
+            find_page(&mut **index, &query, site.visibility(preview.0), position)
+        });
+    let (articles, error) = match found {
+        Ok(page) => (Some(page.map(|(article, _)| article)), None),
+        Err(e) => (None, Some(format!("Can't search for {}: {}", q, e))),
+    };
+    let ctx = ArticlesContext {
+        q,
+        error,
+        page: articles
+            .as_ref()
+            .map(|page| PageContext::new(page.as_ref().map(ArticleContext::new), site.request())),
+    };
+    render_page(&site, "articles/index", &ctx, preview)
+}
+
+// Renders a public page, including unpublished articles for a preview
+fn render_page<T: serde::Serialize>(
+    site: &Site,
+    name: &str,
+    ctx: &T,
+    preview: Preview,
+) -> Result<RawHtml<String>, error::Error> {
+    match preview {
+        Preview(true) => site.preview(name, ctx),
+        Preview(false) => site.render(name, ctx),
+    }
+}
+
+// Pass the id of an article to load it into the editor
+#[get("/admin?<id>")]
+fn serve_admin(
+    site: Site,
+    _admin: Admin,
+    id: Option<String>,
+) -> Result<RawHtml<String>, error::Error> {
+    let mut ctx = IndexContext::default();
+    if let Some(id) = id.filter(|id| validate_id(id).is_ok() && !id.is_empty()) {
+        let query = Query {
+            id: Some(normalize_id(&id)),
+            ..Default::default()
+        };
+        let entry = site.index.lock().unwrap().first(&query);
+        match entry {
+            Ok(entry) => edit_entry(&site, &mut ctx, &*entry)?,
+            Err(_) => ctx.flash = Some(format!("There is no article {}", id)),
+        }
+    }
+    site.preview("admin", &ctx)
+}
+
+// Responds with a file download named filename
+struct Attachment<R> {
+    filename: String,
+    content_type: ContentType,
+    inner: R,
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Attachment<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = self.inner.respond_to(request)?;
+        response.set_header(self.content_type);
+        response.set_header(Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", self.filename),
+        ));
+        Ok(response)
+    }
+}
+
+// The archive is written to a file beside the data directory first, so that
+// writers are only kept out for as long as that takes rather than for as long
+// as the download does
+#[get("/admin/backup")]
+async fn serve_backup(
+    site: Site,
+    _admin: Admin,
+) -> Result<Attachment<rocket::tokio::fs::File>, error::Error> {
+    let app = Arc::clone(&site);
+    let now = site.clock.now();
+    let path = site
+        .paths
+        .data
+        .with_file_name(format!(".backup-{}.tar.gz", Ulid::generate()));
+    let archive = path.clone();
+    let exported = spawn_blocking(move || -> anyhow::Result<File> {
+        let writer = BufWriter::new(File::create(&archive)?);
+        backup::export(&app.paths, now, writer)?;
+        Ok(File::open(&archive)?)
+    })
+    .await;
+    // The open file can still be read once its name is gone
+    let _ = fs::remove_file(&path);
+    let file = exported.map_err(io::Error::other)??;
+
+    Ok(Attachment {
+        filename: format!("ota-{}.tar.gz", now.format("%Y%m%d%H%M%S")),
+        content_type: ContentType::GZIP,
+        inner: rocket::tokio::fs::File::from_std(file),
+    })
+}
+
+#[derive(FromForm)]
+struct RestoreRequest<'r> {
+    archive: TempFile<'r>,
+}
+
+#[post("/admin/restore", data = "<restore_request>")]
+async fn restore_backup(
+    site: Site,
+    _admin: Admin,
+    mut restore_request: Form<RestoreRequest<'_>>,
+) -> Result<RawHtml<String>, error::Error> {
+    let mut ctx = IndexContext::default();
+    let upload = site
+        .paths
+        .data
+        .with_file_name(format!(".upload-{}.tar.gz", Ulid::generate()));
+    let app = Arc::clone(&site);
+    let archive = upload.clone();
+    let restored = async {
+        restore_request.archive.persist_to(&archive).await?;
+        spawn_blocking(move || -> anyhow::Result<backup::Manifest> {
+            let _index = app.index.lock().unwrap();
+            let manifest = backup::import(&app.paths, File::open(archive)?)?;
+            app.reload_templates()?;
+            Ok(manifest)
+        })
+        .await
+        .map_err(io::Error::other)
+    }
+    .await;
+    // The upload goes whether or not it could be restored
+    match fs::remove_file(&upload) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let result = restored?;
+
+    ctx.flash = Some(match result {
+        Ok(manifest) => format!("Restored {} articles", manifest.articles),
+        Err(e) => format!("Error restoring backup: {}", e),
+    });
+    site.preview("admin", &ctx)
+}
+
+#[derive(Serialize, Debug, Default)]
+struct RedirectsContext {
+    flash: Option<String>,
+    redirects: Vec<redirects::Redirect>,
+}
+
+fn render_redirects(site: &Site, flash: Option<String>) -> Result<RawHtml<String>, error::Error> {
+    let ctx = RedirectsContext {
+        flash,
+        redirects: redirects::list(&mut **site.index.lock().unwrap())?,
+    };
+    site.render("admin/redirects", &ctx)
+}
+
+#[get("/admin/redirects")]
+fn serve_redirects(site: Site, _admin: Admin) -> Result<RawHtml<String>, error::Error> {
+    render_redirects(&site, None)
+}
+
+#[derive(FromForm)]
+struct RedirectRequest {
+    from: String,
+    to: String,
+}
+
+#[post("/admin/redirects", data = "<redirect_request>")]
+fn add_redirect(
+    site: Site,
+    _admin: Admin,
+    redirect_request: Form<RedirectRequest>,
+) -> Result<RawHtml<String>, error::Error> {
+    let added = redirects::add(
+        &mut **site.index.lock().unwrap(),
+        redirect_request.from.trim(),
+        redirect_request.to.trim(),
+    );
+    render_redirects(
+        &site,
+        Some(match added {
+            Ok(_) => "Redirect added".into(),
+            Err(e) => format!("Error adding redirect: {}", e),
+        }),
+    )
+}
+
+#[derive(FromForm)]
+struct RemoveRedirectRequest {
+    from: String,
+}
+
+#[post("/admin/redirects/remove", data = "<remove_request>")]
+fn remove_redirect(
+    site: Site,
+    _admin: Admin,
+    remove_request: Form<RemoveRedirectRequest>,
+) -> Result<RawHtml<String>, error::Error> {
+    let removed = redirects::remove(&mut **site.index.lock().unwrap(), &remove_request.from);
+    render_redirects(
+        &site,
+        Some(match removed {
+            Ok(_) => "Redirect removed".into(),
+            Err(e) => format!("Error removing redirect: {}", e),
+        }),
+    )
+}
+
+#[derive(FromForm)]
+struct RemoveRequest {
+    key: String,
+}
+
+#[post("/admin/remove", data = "<remove_request>")]
+fn remove_article(
+    site: Site,
+    _admin: Admin,
+    remove_request: Form<RemoveRequest>,
+) -> Result<RawHtml<String>, error::Error> {
+    let mut ctx = IndexContext::default();
+    let removed = remove_request
+        .key
+        .parse::<Ulid>()
+        .map_err(anyhow::Error::from)
+        .and_then(|key| {
+            site.index.lock().unwrap().remove(&key)?;
+            media::remove_article(&site.paths.data, &key)
+        });
+    ctx.flash = Some(match removed {
+        Ok(_) => "Article removed".into(),
+        Err(e) => format!("Error removing article: {}", e),
+    });
+    site.preview("admin", &ctx)
+}
+
+#[derive(FromForm)]
+struct MediaUpload<'r> {
+    // Key of the article the file belongs to
+    article: String,
+    file: TempFile<'r>,
+}
+
+#[post("/admin/media", data = "<upload>")]
+async fn upload_media(
+    site: Site,
+    _admin: Admin,
+    mut upload: Form<MediaUpload<'_>>,
+) -> Result<RawHtml<String>, error::Error> {
+    let mut ctx = IndexContext::default();
+    let found = match upload.article.parse() {
+        Ok(key) => find_by_key(&site, &key)?.map(|entry| entry.article().key),
+        Err(_) => None,
+    };
+    let article = match found {
+        Some(article) => article,
+        None => {
+            ctx.flash = Some("Save the article before uploading files to it".into());
+            return site.preview("admin", &ctx);
+        }
+    };
+
+    // Browsers send the name of the file as it was on disk, it is cleaned up
+    // when stored
+    let file = &upload.file;
+    let name = match file.raw_name() {
+        Some(name) => name.dangerous_unsafe_unsanitized_raw().to_string(),
+        None => {
+            let extension = file.content_type().and_then(|t| t.extension());
+            match extension {
+                Some(extension) => format!("file.{}", extension),
+                None => "file".to_string(),
+            }
+        }
+    };
+    let staged = media::staging_path(&site.paths.data);
+    fs::create_dir_all(staged.parent().unwrap())?;
+    upload.file.persist_to(&staged).await?;
+
+    let app = Arc::clone(&site);
+    let now = site.clock.now();
+    let stored =
+        spawn_blocking(move || media::store(&app.paths.data, article, &name, &staged, now))
+            .await
+            .map_err(io::Error::other)?;
+    ctx.flash = Some(match stored {
+        Ok(media) => format!("Uploaded {}", media.url()),
+        Err(e) => format!("Error uploading file: {}", e),
+    });
+    if let Some(entry) = find_by_key(&site, &article)? {
+        edit_entry(&site, &mut ctx, &*entry)?;
+    }
+    site.preview("admin", &ctx)
+}
+
+#[derive(FromForm)]
+struct RemoveMediaRequest {
+    key: String,
+}
+
+#[post("/admin/media/remove", data = "<remove_request>")]
+fn remove_media(
+    site: Site,
+    _admin: Admin,
+    remove_request: Form<RemoveMediaRequest>,
+) -> Result<RawHtml<String>, error::Error> {
+    let mut ctx = IndexContext::default();
+    let removed = remove_request
+        .key
+        .parse::<Ulid>()
+        .map_err(anyhow::Error::from)
+        .and_then(|key| media::remove(&site.paths.data, &key));
+    match removed {
+        Ok(article) => {
+            ctx.flash = Some("File removed".into());
+            if let Some(entry) = find_by_key(&site, &article)? {
+                edit_entry(&site, &mut ctx, &*entry)?;
+            }
+        }
+        Err(e) => ctx.flash = Some(format!("Error removing file: {}", e)),
+    }
+    site.preview("admin", &ctx)
+}
+
+// Media are served by the hash of their content with the type recorded for
+// the upload of that name, names that were never uploaded are not found.
+// Images can be resized or converted with the query, see images::Transform.
+#[get("/media/<hash>/<name>?<transform..>")]
+async fn serve_media(
+    site: Site,
+    hash: &str,
+    name: &str,
+    transform: Transform,
+) -> Result<Option<MediaFile>, error::Error> {
+    let media = match media::find(&site.paths.data, hash, name)? {
+        Some(media) => media,
+        None => return Ok(None),
+    };
+    if transform.is_empty() {
+        let content_type =
+            ContentType::parse_flexible(&media.content_type).unwrap_or(ContentType::Binary);
+        let file = media::open(&site.paths.data, hash)?;
+        return Ok(file.map(|file| MediaFile::new(file, content_type)));
+    }
+    serve_derivative(site, hash, transform).await
+}
+
+#[get("/media/<hash>?<transform..>", rank = 2)]
+async fn serve_media_by_hash(
+    site: Site,
+    hash: &str,
+    transform: Transform,
+) -> Result<Option<MediaFile>, error::Error> {
+    if transform.is_empty() {
+        let file = match media::open(&site.paths.data, hash)? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let content_type = images::sniff(&site.paths.data, hash)?;
+        return Ok(Some(MediaFile::new(file, content_type)));
+    }
+    serve_derivative(site, hash, transform).await
+}
+
+// Decoding and resizing take a while, so they're kept off the async workers
+async fn serve_derivative(
+    site: Site,
+    hash: &str,
+    transform: Transform,
+) -> Result<Option<MediaFile>, error::Error> {
+    let (app, hash) = (Arc::clone(&site), hash.to_string());
+    let derived = spawn_blocking(move || {
+        images::derive(&app.paths.data, &hash, &transform, &app.image_widths)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok(derived.map(|(file, format)| MediaFile::new(file, format.content_type())))
+}
+
+// Lists persisted changes, pass the last sequence seen as after to resume
+#[get("/admin/changes?<after>")]
+fn serve_changes(
+    site: Site,
+    _admin: Admin,
+    after: Option<u64>,
+) -> Result<RawJson<String>, error::Error> {
+    let changes = site.index.lock().unwrap().changes(after.unwrap_or(0))?;
+    Ok(RawJson(serde_json::to_string(&changes)?))
+}
+
+// The Last-Event-ID header sent by reconnecting event stream clients
+struct LastEventId(u64);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let sequence = request
+            .headers()
+            .get_one("Last-Event-ID")
+            .and_then(|id| id.parse().ok());
+        Outcome::Success(LastEventId(sequence.unwrap_or(0)))
+    }
+}
+
+// How often a change stream checks whether its client is still connected
+const CHANGES_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Streams changes as server sent events, first catching up from the
+// Last-Event-ID and then following live changes
+#[get("/admin/changes/stream")]
+fn stream_changes(
+    site: Site,
+    _admin: Admin,
+    last_event: LastEventId,
+) -> Result<EventStream![], error::Error> {
+    let (live, backlog) = {
+        let mut index = site.index.lock().unwrap();
+        let live = index.subscribe();
+        (live, index.changes(last_event.0)?)
+    };
+    let (sender, mut receiver) = mpsc::channel(16);
+    // Wakes up now and then to notice the client has gone, which otherwise
+    // only shows when the next change is sent
+    spawn_blocking(move || loop {
+        match live.recv_timeout(CHANGES_POLL_INTERVAL) {
+            Ok(change) => {
+                if sender.blocking_send(change).is_err() {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if !sender.is_closed() => continue,
+            Err(_) => break,
+        }
+    });
+
+    let mut sequence = last_event.0;
+    Ok(EventStream! {
+        for change in backlog {
+            sequence = change.sequence;
+            yield Event::data(serde_json::to_string(&change).unwrap_or_default())
+                .id(change.sequence.to_string());
+        }
+        while let Some(change) = receiver.recv().await {
+            if change.sequence <= sequence {
+                continue;
+            }
+            yield Event::data(serde_json::to_string(&change).unwrap_or_default())
+                .id(change.sequence.to_string());
+        }
+    })
 }
 
 #[get("/index")]
-fn serve_index() -> Template {
+fn serve_index(site: Site, preview: Preview) -> Result<RawHtml<String>, error::Error> {
     let ctx = IndexContext::default();
-    Template::render("index", ctx)
+    render_page(&site, "index", &ctx, preview)
+}
+
+#[get("/static/<path..>")]
+async fn serve_static(site: Site, path: PathBuf) -> Option<NamedFile> {
+    NamedFile::open(site.paths.statics.join(path)).await.ok()
 }
 
 #[launch]
 fn server() -> _ {
-    let index = Local::new("data").unwrap();
-    let state = Arc::new(App {
-        index: Arc::new(Mutex::new(Box::new(index))),
-    });
-
     rocket::build()
         .mount(
             "/",
             routes![
                 redirect_to_root,
                 create_article,
+                api_create_article,
                 serve_article,
+                serve_article_path,
                 serve_articles,
                 serve_index,
                 serve_admin,
+                serve_backup,
+                restore_backup,
+                remove_article,
+                serve_redirects,
+                add_redirect,
+                remove_redirect,
+                serve_changes,
+                stream_changes,
+                serve_static,
+                upload_media,
+                remove_media,
+                serve_media,
+                serve_media_by_hash,
             ],
         )
-        .mount("/static", FileServer::from(relative!("site")))
-        .manage(state.clone())
-        .attach(Template::custom(move |engines| {
-            let inner_state = Arc::clone(&state);
-            register_helpers(&mut engines.handlebars, inner_state);
+        .register("/", catchers![auth::unauthorized])
+        .attach(AdHoc::try_on_ignite("Sites", |rocket| async {
+            match Sites::from_figment(rocket.figment()) {
+                Ok(sites) => Ok(rocket.manage(sites)),
+                Err(e) => {
+                    eprintln!("error loading sites: {:?}", e);
+                    Err(rocket)
+                }
+            }
         }))
 }
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+    use tempdir::TempDir;
+
+    use crate::articles::{Article, NewArticleRequest};
+    use crate::site::Sites;
+    use crate::*;
+
+    #[test]
+    fn test_aliases_redirect() {
+        let dir = TempDir::new("main_aliases").unwrap();
+        let figment = rocket::Config::figment()
+            .merge(("data_dir", dir.path().join("data")))
+            .merge(("static_dir", dir.path().join("site")));
+        let client = Client::tracked(server().configure(figment)).unwrap();
+        let app = client
+            .rocket()
+            .state::<Sites>()
+            .unwrap()
+            .select(None)
+            .unwrap();
+        app.index
+            .lock()
+            .unwrap()
+            .update(&Article::new(&NewArticleRequest {
+                id: "blog/new".to_string(),
+                aliases: "old".to_string(),
+                body: "hello".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        for path in ["/old", "/articles/@old"] {
+            let response = client.get(path).dispatch();
+            assert_eq!(response.status(), Status::MovedPermanently);
+            assert_eq!(response.headers().get_one("Location"), Some("/blog/new"));
+        }
+        for path in ["/blog/new", "/articles/@blog/new"] {
+            assert_eq!(client.get(path).dispatch().status(), Status::Ok);
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use std::fs::{self, create_dir_all, rename, File};
+use std::io::{self, ErrorKind, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::tokio::io::AsyncReadExt;
+use rusty_ulid::Ulid;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::index::local::DirLock;
+
+// Media are files uploaded alongside an article, such as its images. Their
+// content is stored once under data/media/content by its hash, each upload is
+// recorded under data/media/articles/<article key>/<media key>.yaml, with a
+// copy under data/media/hashes/<hash> so it is found from its URL, and the
+// records go when their article is removed.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Media {
+    pub key: Ulid,
+    // The key of the article the media belongs to
+    pub article: Ulid,
+    pub hash: String,
+    // The file name it was uploaded with, cleaned up to be part of its URL
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub uploaded: DateTime<Utc>,
+}
+
+impl Media {
+    // Content never changes under a hash, so the URL can be cached forever
+    pub fn url(&self) -> String {
+        format!("/media/{}/{}", self.hash, self.name)
+    }
+}
+
+fn media_root(data: &Path) -> PathBuf {
+    data.join("media")
+}
+
+pub(crate) fn content_path(data: &Path, hash: &str) -> PathBuf {
+    media_root(data).join("content").join(&hash[..2]).join(hash)
+}
+
+// Resized and converted copies of the content under hash, see images
+pub(crate) fn derivatives_dir(data: &Path, hash: &str) -> PathBuf {
+    media_root(data)
+        .join("derivatives")
+        .join(&hash[..2])
+        .join(hash)
+}
+
+// Hashes come from URLs, so they are checked before they become paths
+pub(crate) fn is_hash(hash: &str) -> bool {
+    hash.len() == blake3::OUT_LEN * 2 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn article_dir(data: &Path, article: &Ulid) -> PathBuf {
+    media_root(data).join("articles").join(article.to_string())
+}
+
+// The records of every upload of the content under hash
+fn hash_dir(data: &Path, hash: &str) -> PathBuf {
+    media_root(data).join("hashes").join(&hash[..2]).join(hash)
+}
+
+fn record_path(dir: &Path, key: &Ulid) -> PathBuf {
+    dir.join(format!("{}.yaml", key))
+}
+
+// Removes the record of key from dir, and dir once it is empty
+fn remove_record(dir: &Path, key: &Ulid) -> Result<()> {
+    match fs::remove_file(record_path(dir, key)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let _ = fs::remove_dir(dir);
+    Ok(())
+}
+
+// Where an upload is written before it is stored, hidden so backups and
+// listings skip it
+pub fn staging_path(data: &Path) -> PathBuf {
+    media_root(data).join(format!(".upload-{}", Ulid::generate()))
+}
+
+// Keeps the letters, digits, -, _ and . of the last part of a file name, so it
+// is safe in a URL and on disk
+pub fn clean_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = name
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                true => c,
+                false => '-',
+            },
+        )
+        .collect();
+    match cleaned.trim_start_matches('.') {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+// The type of an upload is taken from its name when it is stored, and the
+// type recorded then is what it is served with
+pub fn content_type_of(name: &str) -> ContentType {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(ContentType::from_extension)
+        .unwrap_or(ContentType::Binary)
+}
+
+// Stores the file at staged as media of article, moving it into the content
+// store unless identical content is already there
+pub fn store(
+    data: &Path,
+    article: Ulid,
+    name: &str,
+    staged: &Path,
+    now: DateTime<Utc>,
+) -> Result<Media> {
+    let mut hasher = blake3::Hasher::new();
+    let size = io::copy(&mut File::open(staged)?, &mut hasher)?;
+    let hash = hasher.finalize().to_hex().to_string();
+    let name = clean_name(name);
+    let media = Media {
+        key: Ulid::generate(),
+        article,
+        content_type: content_type_of(&name).to_string(),
+        name,
+        hash,
+        size,
+        uploaded: now,
+    };
+
+    let _lock = DirLock::exclusive(data)?;
+    let path = content_path(data, &media.hash);
+    if path.is_file() {
+        fs::remove_file(staged)?;
+    } else {
+        create_dir_all(path.parent().unwrap())?;
+        rename(staged, &path)?;
+    }
+    let record = serde_yaml::to_string(&media)?;
+    for dir in [article_dir(data, &article), hash_dir(data, &media.hash)] {
+        create_dir_all(&dir)?;
+        fs::write(record_path(&dir, &media.key), &record)?;
+    }
+    Ok(media)
+}
+
+// Lists the media of article in the order they were uploaded
+pub fn list(data: &Path, article: &Ulid) -> Result<Vec<Media>> {
+    let _lock = DirLock::shared(data)?;
+    read_records(&article_dir(data, article))
+}
+
+fn read_records(dir: &Path) -> Result<Vec<Media>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut media = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "yaml") {
+            media.push(serde_yaml::from_str::<Media>(&fs::read_to_string(path)?)?);
+        }
+    }
+    media.sort_by_key(|media| (media.uploaded, media.key));
+    Ok(media)
+}
+
+// Every media record of every article
+fn all_records(data: &Path) -> Result<Vec<Media>> {
+    let root = media_root(data).join("articles");
+    let entries = match fs::read_dir(&root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut media = vec![];
+    for entry in entries {
+        media.extend(read_records(&entry?.path())?);
+    }
+    Ok(media)
+}
+
+// Finds an upload of the content under hash by the name it was given
+pub fn find(data: &Path, hash: &str, name: &str) -> Result<Option<Media>> {
+    if !is_hash(hash) {
+        return Ok(None);
+    }
+    let _lock = DirLock::shared(data)?;
+    Ok(read_records(&hash_dir(data, &hash.to_ascii_lowercase()))?
+        .into_iter()
+        .find(|media| media.name == name))
+}
+
+// Opens the content stored under hash
+pub fn open(data: &Path, hash: &str) -> Result<Option<File>> {
+    if !is_hash(hash) {
+        return Ok(None);
+    }
+    let _lock = DirLock::shared(data)?;
+    match File::open(content_path(data, &hash.to_ascii_lowercase())) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Removes a single upload, returning the article it belonged to
+pub fn remove(data: &Path, key: &Ulid) -> Result<Ulid> {
+    let _lock = DirLock::exclusive(data)?;
+    let media = all_records(data)?
+        .into_iter()
+        .find(|media| &media.key == key)
+        .ok_or_else(|| anyhow!("there is no media {}", key))?;
+    remove_record(&article_dir(data, &media.article), key)?;
+    remove_record(&hash_dir(data, &media.hash), key)?;
+    let article = media.article;
+    remove_unused_content(data, &[media])?;
+    Ok(article)
+}
+
+// Removes every upload of article, called when the article is removed
+pub fn remove_article(data: &Path, article: &Ulid) -> Result<()> {
+    let _lock = DirLock::exclusive(data)?;
+    let dir = article_dir(data, article);
+    let removed = read_records(&dir)?;
+    for media in removed.iter() {
+        remove_record(&hash_dir(data, &media.hash), &media.key)?;
+    }
+    match fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    remove_unused_content(data, &removed)
+}
+
+// Content is shared between identical uploads, so it and its derivatives are
+// only removed once no upload refers to it
+fn remove_unused_content(data: &Path, removed: &[Media]) -> Result<()> {
+    for media in removed.iter() {
+        if hash_dir(data, &media.hash).exists() {
+            continue;
+        }
+        match fs::remove_file(content_path(data, &media.hash)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        match fs::remove_dir_all(derivatives_dir(data, &media.hash)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// The part of a file asked for with a Range header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    // First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Parses a Range header for a file of size bytes. Only single byte ranges
+// are supported, anything else is answered with the whole file as the
+// standard allows.
+fn parse_range(header: Option<&str>, size: u64) -> ByteRange {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // The last end bytes
+        (Err(_), Ok(0)) if start.is_empty() => return ByteRange::Unsatisfiable,
+        (Err(_), Ok(last)) if start.is_empty() => (size.saturating_sub(last), u64::MAX),
+        (Ok(start), Err(_)) if end.is_empty() => (start, u64::MAX),
+        (Ok(start), Ok(end)) if start <= end => (start, end),
+        _ => return ByteRange::Full,
+    };
+    match start >= size {
+        true => ByteRange::Unsatisfiable,
+        false => ByteRange::Partial(start, end.min(size - 1)),
+    }
+}
+
+// Serves stored media, honouring Range requests so that audio and video can
+// be seeked without downloading everything first
+pub struct MediaFile {
+    file: File,
+    content_type: ContentType,
+}
+
+impl MediaFile {
+    pub fn new(file: File, content_type: ContentType) -> Self {
+        MediaFile { file, content_type }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for MediaFile {
+    fn respond_to(mut self, request: &'r Request<'_>) -> response::Result<'static> {
+        let size = self
+            .file
+            .metadata()
+            .map_err(|_| Status::InternalServerError)?
+            .len();
+        let mut response = Response::build();
+        response
+            .header(self.content_type)
+            .raw_header("Accept-Ranges", "bytes")
+            .raw_header("Cache-Control", "public, max-age=31536000, immutable")
+            .raw_header("X-Content-Type-Options", "nosniff");
+
+        match parse_range(request.headers().get_one("Range"), size) {
+            ByteRange::Full => {
+                let file = rocket::tokio::fs::File::from_std(self.file);
+                response.sized_body(None, file);
+            }
+            ByteRange::Partial(start, end) => {
+                self.file
+                    .seek(SeekFrom::Start(start))
+                    .map_err(|_| Status::InternalServerError)?;
+                let length = end - start + 1;
+                let file = rocket::tokio::fs::File::from_std(self.file).take(length);
+                // A streamed body has no size of its own, so the length of
+                // the range is given explicitly
+                response
+                    .status(Status::PartialContent)
+                    .raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, size))
+                    .raw_header("Content-Length", length.to_string())
+                    .streamed_body(file);
+            }
+            ByteRange::Unsatisfiable => {
+                response
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", size));
+            }
+        }
+        response.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::media::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_clean_name() {
+        assert_eq!(clean_name("photo.JPG"), "photo.JPG");
+        assert_eq!(
+            clean_name("C:\\Users\\me\\my photo (1).png"),
+            "my-photo--1-.png"
+        );
+        assert_eq!(clean_name("../../.htaccess"), "htaccess");
+        assert_eq!(clean_name(""), "file");
+        assert_eq!(content_type_of("a.png"), ContentType::PNG);
+        assert_eq!(content_type_of("a"), ContentType::Binary);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        use ByteRange::*;
+        assert_eq!(parse_range(None, 10), Full);
+        assert_eq!(parse_range(Some("bytes=0-3"), 10), Partial(0, 3));
+        assert_eq!(parse_range(Some("bytes=5-"), 10), Partial(5, 9));
+        assert_eq!(parse_range(Some("bytes=-4"), 10), Partial(6, 9));
+        assert_eq!(parse_range(Some("bytes=-40"), 10), Partial(0, 9));
+        assert_eq!(parse_range(Some("bytes=8-100"), 10), Partial(8, 9));
+        assert_eq!(parse_range(Some("bytes=10-"), 10), Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-0"), 10), Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-1,4-5"), 10), Full);
+        assert_eq!(parse_range(Some("bytes=5-1"), 10), Full);
+        assert_eq!(parse_range(Some("items=0-1"), 10), Full);
+    }
+
+    #[test]
+    fn test_store_and_remove() {
+        let dir = TempDir::new("media_test").unwrap();
+        let data = dir.path();
+        let (first, second) = (Ulid::generate(), Ulid::generate());
+        let upload = |article: Ulid, name: &str, content: &str| {
+            let staged = staging_path(data);
+            fs::create_dir_all(staged.parent().unwrap()).unwrap();
+            fs::write(&staged, content).unwrap();
+            let media = store(data, article, name, &staged, Utc::now()).unwrap();
+            assert!(!staged.exists());
+            media
+        };
+
+        let logo = upload(first, "logo.png", "png bytes");
+        assert_eq!(logo.content_type, "image/png");
+        assert_eq!(logo.size, 9);
+        assert!(logo.url().ends_with("/logo.png"));
+        let shared = upload(second, "copy.png", "png bytes");
+        let notes = upload(first, "notes.txt", "notes");
+        assert_eq!(shared.hash, logo.hash);
+        assert_eq!(list(data, &first).unwrap(), [logo.clone(), notes.clone()]);
+        assert!(open(data, &logo.hash).unwrap().is_some());
+        assert_eq!(
+            find(data, &logo.hash, "logo.png").unwrap(),
+            Some(logo.clone())
+        );
+        assert_eq!(
+            find(data, &logo.hash, "copy.png").unwrap(),
+            Some(shared.clone())
+        );
+        assert!(find(data, &logo.hash, "logo.html").unwrap().is_none());
+        assert!(find(data, &notes.hash, "logo.png").unwrap().is_none());
+
+        // Content shared with another article stays
+        remove_article(data, &first).unwrap();
+        assert!(list(data, &first).unwrap().is_empty());
+        assert!(open(data, &logo.hash).unwrap().is_some());
+        assert!(open(data, &notes.hash).unwrap().is_none());
+
+        assert_eq!(remove(data, &shared.key).unwrap(), second);
+        assert!(open(data, &logo.hash).unwrap().is_none());
+        assert!(!hash_dir(data, &logo.hash).exists());
+        assert!(remove(data, &shared.key).is_err());
+        assert!(open(data, "../../etc").unwrap().is_none());
+    }
+}
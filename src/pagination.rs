@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rocket::http::RawStr;
+use rusty_ulid::Ulid;
+use serde::Serialize;
+
+use crate::articles::{Article, Visibility};
+use crate::index::{Entry, Index};
+use crate::query::Query;
+use crate::templates::RequestInfo;
+
+// Pages hold this many articles unless the query has a limit
+pub const DEFAULT_PAGE_SIZE: usize = 10;
+
+// How many page numbers are linked either side of the current page
+const NUMBERS_AROUND: usize = 3;
+
+// Where a page starts, read from ?page=3, ?after=<key> or ?before=<key>.
+// Numbered pages move along as articles are published, while the pages after
+// or before an article stay put, so prev and next links use those.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Position {
+    Number(usize),
+    After(Ulid),
+    Before(Ulid),
+}
+
+// The names of the request parameters a position is read from
+const POSITION_PARAMS: [&str; 3] = ["page", "after", "before"];
+
+impl Position {
+    pub fn from_params(params: &BTreeMap<String, String>) -> Self {
+        let key = |name: &str| params.get(name).and_then(|key| key.parse().ok());
+        if let Some(key) = key("after") {
+            Position::After(key)
+        } else if let Some(key) = key("before") {
+            Position::Before(key)
+        } else {
+            let number = params.get("page").and_then(|number| number.parse().ok());
+            Position::Number(number.unwrap_or(1))
+        }
+    }
+}
+
+// A page of matches and where it sits among all of them
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // Every match, on any page
+    pub total: usize,
+    pub size: usize,
+    // From 1, the page the first item falls on
+    pub number: usize,
+    pub pages: usize,
+    // Keys to ask for the pages before and after this one with, when there
+    // are any
+    pub before: Option<Ulid>,
+    pub after: Option<Ulid>,
+}
+
+impl<T> Page<T> {
+    pub fn as_ref(&self) -> Page<&T> {
+        Page {
+            items: self.items.iter().collect(),
+            total: self.total,
+            size: self.size,
+            number: self.number,
+            pages: self.pages,
+            before: self.before,
+            after: self.after,
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            total: self.total,
+            size: self.size,
+            number: self.number,
+            pages: self.pages,
+            before: self.before,
+            after: self.after,
+        }
+    }
+}
+
+// Cuts the page at position out of matches. A page after or before an article
+// that has gone starts from the beginning.
+pub fn paginate<T>(
+    matches: Vec<T>,
+    size: usize,
+    position: Position,
+    key: impl Fn(&T) -> Ulid,
+) -> Page<T> {
+    let (total, size) = (matches.len(), size.max(1));
+    let index_of = |wanted: Ulid| matches.iter().position(|m| key(m) == wanted);
+    let (start, end) = match position {
+        Position::Number(number) => {
+            let start = number.saturating_sub(1).saturating_mul(size).min(total);
+            (start, start.saturating_add(size).min(total))
+        }
+        Position::After(wanted) => {
+            let start = index_of(wanted).map_or(0, |i| i + 1);
+            (start, (start + size).min(total))
+        }
+        Position::Before(wanted) => match index_of(wanted) {
+            Some(end) => (end.saturating_sub(size), end),
+            None => (0, size.min(total)),
+        },
+    };
+
+    let items: Vec<T> = matches.into_iter().skip(start).take(end - start).collect();
+    Page {
+        before: items.first().filter(|_| start > 0).map(&key),
+        after: items.last().filter(|_| end < total).map(&key),
+        items,
+        total,
+        size,
+        // The page its first item would be on when counting from the start
+        number: start / size + 1,
+        pages: total.div_ceil(size).max(1),
+    }
+}
+
+// Finds the page at position of the articles matching query, counting only
+// those that may be seen. The limit of the query is the size of its pages.
+pub fn find_page(
+    index: &mut dyn Index,
+    query: &Query,
+    visibility: Visibility,
+    position: Position,
+) -> Result<Page<(Article, Box<dyn Entry>)>> {
+    let mut matches: Vec<_> = index
+        .search(query)?
+        .map(|entry| (entry.article(), entry))
+        .filter(|(article, _)| visibility.shows(article))
+        .collect();
+    query.sort(&mut matches, |(article, _)| article);
+    let size = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    Ok(paginate(matches, size, position, |(article, _)| {
+        article.key
+    }))
+}
+
+// Links to the pages around a page, keeping the other parameters of the
+// request such as a search
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct PageLinks {
+    pub prev: Option<String>,
+    pub next: Option<String>,
+    pub numbers: Vec<PageNumber>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct PageNumber {
+    pub number: usize,
+    pub url: String,
+    pub current: bool,
+}
+
+impl PageLinks {
+    pub fn new<T>(page: &Page<T>, request: &RequestInfo) -> Self {
+        let first = page.number.saturating_sub(NUMBERS_AROUND).max(1);
+        let last = (page.number + NUMBERS_AROUND).min(page.pages);
+        PageLinks {
+            prev: page
+                .before
+                .map(|key| page_url(request, "before", &key.to_string())),
+            next: page
+                .after
+                .map(|key| page_url(request, "after", &key.to_string())),
+            numbers: (first..=last)
+                .map(|number| PageNumber {
+                    number,
+                    url: page_url(request, "page", &number.to_string()),
+                    current: number == page.number,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn page_url(request: &RequestInfo, name: &str, value: &str) -> String {
+    let mut params = request.query.clone();
+    params.retain(|param, _| !POSITION_PARAMS.contains(&param.as_str()));
+    params.insert(name.to_string(), value.to_string());
+    let query: Vec<String> = params
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}={}",
+                RawStr::new(name).percent_encode(),
+                RawStr::new(value).percent_encode()
+            )
+        })
+        .collect();
+    format!("{}?{}", request.path, query.join("&"))
+}
+
+// A page as templates see it, along with its links
+#[derive(Serialize)]
+pub struct PageContext<T: Serialize> {
+    #[serde(flatten)]
+    pub page: Page<T>,
+    #[serde(flatten)]
+    pub links: PageLinks,
+}
+
+impl<T: Serialize> PageContext<T> {
+    pub fn new(page: Page<T>, request: &RequestInfo) -> Self {
+        let links = PageLinks::new(&page, request);
+        PageContext { page, links }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::articles::NewArticleRequest;
+    use crate::pagination::*;
+    use chrono::Utc;
+    use chrono_tz::Tz;
+
+    #[test]
+    fn test_paginate() {
+        let keys: Vec<Ulid> = (0..7).map(|_| Ulid::generate()).collect();
+        let page = |position| paginate(keys.clone(), 3, position, |key| *key);
+
+        let first = page(Position::Number(1));
+        assert_eq!(first.items, keys[..3]);
+        assert_eq!((first.total, first.number, first.pages), (7, 1, 3));
+        assert_eq!((first.before, first.after), (None, Some(keys[2])));
+
+        let last = page(Position::Number(3));
+        assert_eq!(last.items, keys[6..]);
+        assert_eq!((last.before, last.after), (Some(keys[6]), None));
+        assert!(page(Position::Number(9)).items.is_empty());
+
+        assert_eq!(page(Position::After(keys[2])).items, keys[3..6]);
+        assert_eq!(page(Position::Before(keys[3])).items, keys[..3]);
+        assert_eq!(page(Position::Before(keys[1])).items, keys[..1]);
+        // An article that has gone starts from the beginning
+        assert_eq!(page(Position::After(Ulid::generate())).items, keys[..3]);
+
+        // Articles published in front of a page don't move the pages after it
+        let mut published = vec![Ulid::generate(), Ulid::generate()];
+        published.extend(&keys);
+        let next = paginate(published, 3, Position::After(keys[2]), |key| *key);
+        assert_eq!(next.items, keys[3..6]);
+        assert_eq!(next.number, 2);
+        assert_eq!(page(Position::After(keys[1])).number, 1);
+
+        let empty = paginate(Vec::<Ulid>::new(), 3, Position::Number(1), |key| *key);
+        assert_eq!((empty.total, empty.number, empty.pages), (0, 1, 1));
+    }
+
+    #[test]
+    fn test_paginate_equal_sort_values() {
+        // Articles published in the same second share an epoch
+        let now = Utc::now();
+        let mut articles: Vec<Article> = (0..7)
+            .map(|i| {
+                let request = NewArticleRequest {
+                    id: format!("post-{}", i),
+                    ..Default::default()
+                };
+                Article::new_at(&request, now, Tz::UTC)
+            })
+            .collect();
+        let query: Query = "sort:-epoch".try_into().unwrap();
+        let page = |articles: &[Article], position| {
+            let mut matches = articles.to_vec();
+            query.sort(&mut matches, |article| article);
+            paginate(matches, 3, position, |article| article.key)
+        };
+        let keys = |page: Page<Article>| page.items.iter().map(|a| a.key).collect::<Vec<_>>();
+
+        let after = page(&articles, Position::Number(1)).after.unwrap();
+        let second = keys(page(&articles, Position::Number(2)));
+        assert!(!second.contains(&after));
+        // The page after an article is the same however the index lists them
+        articles.reverse();
+        assert_eq!(keys(page(&articles, Position::After(after))), second);
+    }
+
+    #[test]
+    fn test_page_links() {
+        let keys: Vec<Ulid> = (0..50).map(|_| Ulid::generate()).collect();
+        let page = paginate(keys.clone(), 5, Position::Number(2), |key| *key);
+        let request = RequestInfo {
+            path: "/blog".to_string(),
+            query: [
+                ("page".to_string(), "2".to_string()),
+                ("q".to_string(), "tag:news sort:-epoch".to_string()),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(Position::from_params(&request.query), Position::Number(2));
+
+        let links = PageLinks::new(&page, &request);
+        assert_eq!(
+            links.prev,
+            Some(format!("/blog?before={}&q=tag:news%20sort:-epoch", keys[5]))
+        );
+        assert_eq!(
+            links.next,
+            Some(format!("/blog?after={}&q=tag:news%20sort:-epoch", keys[9]))
+        );
+        let numbers: Vec<_> = links.numbers.iter().map(|n| n.number).collect();
+        assert_eq!(numbers, [1, 2, 3, 4, 5]);
+        assert!(links.numbers[1].current);
+        assert_eq!(
+            links.numbers[0].url,
+            "/blog?page=1&q=tag:news%20sort:-epoch"
+        );
+    }
+}
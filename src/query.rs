@@ -1,21 +1,172 @@
+use std::cmp::Ordering;
 use std::convert::TryFrom;
 
 use anyhow::Result;
 use thiserror::Error;
 
+use crate::articles::{normalize_id, Article, LinkKind, PropertySet, Status};
+
 #[derive(Clone, Debug, Default)]
 pub struct Query {
     pub id: Option<String>,
+    // Matches articles below this id, @blog/* for the direct children of
+    // blog and @blog/** for everything below it
+    pub parent: Option<String>,
+    pub recursive: bool,
+    // Matches articles whose body hash starts with this
+    pub hash: Option<String>,
+    pub status: Option<Status>,
     pub properties: Vec<PropertyFilter>,
     pub tags: Vec<String>,
+    // Matches articles linking to an article, parent=@docs for the children
+    // of docs or links=@docs for links of any kind
+    pub links: Vec<LinkFilter>,
+    // sort:title orders matches by a field, sort:-epoch in reverse
+    pub sort: Option<Sort>,
+    // limit:10 keeps the first ten matches
+    pub limit: Option<usize>,
 }
 
 pub const ALL: &Query = &Query {
     id: None,
+    parent: None,
+    recursive: false,
+    hash: None,
+    status: None,
     properties: vec![],
     tags: vec![],
+    links: vec![],
+    sort: None,
+    limit: None,
 };
 
+impl Query {
+    // Checks whether an article satisfies every filter in the query
+    pub fn matches(&self, article: &Article) -> bool {
+        if let Some(ref id) = self.id {
+            if &article.id != id {
+                return false;
+            }
+        }
+        if let Some(ref parent) = self.parent {
+            let child = match article
+                .id
+                .strip_prefix(parent.as_str())
+                .and_then(|rest| rest.strip_prefix('/'))
+            {
+                Some(child) => child,
+                None => return false,
+            };
+            if !self.recursive && child.contains('/') {
+                return false;
+            }
+        }
+        self.matches_filters(article)
+    }
+
+    // Like matches, but leaves the id to be matched by the index
+    pub fn matches_filters(&self, article: &Article) -> bool {
+        if self.status.is_some_and(|status| status != article.status) {
+            return false;
+        }
+        if let Some(ref hash) = self.hash {
+            if !article.hash.starts_with(hash.as_str()) {
+                return false;
+            }
+        }
+        self.tags.iter().all(|tag| article.tags.contains(tag))
+            && self.links.iter().all(|filter| filter.matches(article))
+            && self
+                .properties
+                .iter()
+                .all(|filter| filter.matches(&article.properties))
+    }
+
+    // Puts matches in the order asked for and drops those past the limit
+    pub fn arrange<T>(&self, matches: &mut Vec<T>, article: impl Fn(&T) -> &Article) {
+        self.sort(matches, article);
+        if let Some(limit) = self.limit {
+            matches.truncate(limit);
+        }
+    }
+
+    // Without a sort matches stay in the order of the index
+    pub fn sort<T>(&self, matches: &mut [T], article: impl Fn(&T) -> &Article) {
+        if let Some(ref sort) = self.sort {
+            matches.sort_by(|a, b| sort.compare(article(a), article(b)));
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sort {
+    // id, title or the name of a property
+    pub field: String,
+    pub descending: bool,
+}
+
+impl Sort {
+    fn value<'a>(&self, article: &'a Article) -> Option<&'a str> {
+        match self.field.as_str() {
+            "id" => Some(&article.id),
+            "title" => Some(&article.title),
+            field => article.properties.get(field).map(String::as_str),
+        }
+    }
+
+    // Articles without the field come last either way. Ties are broken by
+    // key, so that articles created in the same second keep their order.
+    pub fn compare(&self, a: &Article, b: &Article) -> Ordering {
+        let ordering = match (self.value(a), self.value(b)) {
+            (Some(a), Some(b)) => compare_values(a, b),
+            (Some(_), None) => return Ordering::Less,
+            (None, Some(_)) => return Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        let ordering = ordering.then_with(|| a.key.cmp(&b.key));
+        match self.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    }
+}
+
+// Numbers are compared numerically, anything else as plain strings
+fn compare_values(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkFilter {
+    // Links of any kind match without a kind
+    pub kind: Option<LinkKind>,
+    // The ids of the linked article, the index adds its aliases so that links
+    // made before a rename still count
+    pub ids: Vec<String>,
+}
+
+impl LinkFilter {
+    // The name a filter of any kind is written with
+    pub const ANY: &'static str = "links";
+
+    pub fn kinds(&self) -> Vec<LinkKind> {
+        match self.kind {
+            Some(kind) => vec![kind],
+            None => LinkKind::ALL.to_vec(),
+        }
+    }
+
+    fn matches(&self, article: &Article) -> bool {
+        self.kinds()
+            .into_iter()
+            .flat_map(|kind| article.links(kind))
+            .any(|target| self.ids.contains(target))
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct PropertyFilter {
     field: String,
@@ -29,6 +180,22 @@ enum PropertyOperator {
     Gt(String),
 }
 
+impl PropertyFilter {
+    fn matches(&self, properties: &PropertySet) -> bool {
+        let value = match properties.get(&self.field) {
+            Some(value) => value,
+            None => return false,
+        };
+        match self.operator {
+            PropertyOperator::Equals(ref argument) => value == argument,
+            PropertyOperator::Lt(ref argument) => compare_values(value, argument) == Ordering::Less,
+            PropertyOperator::Gt(ref argument) => {
+                compare_values(value, argument) == Ordering::Greater
+            }
+        }
+    }
+}
+
 #[derive(Error, Debug, PartialEq, Eq)]
 pub enum QueryParseError {
     #[error("missing right hand side of property filter")]
@@ -37,6 +204,16 @@ pub enum QueryParseError {
     MissingOperatorField,
     #[error("duplicate id filter")]
     DuplicateID,
+    #[error("duplicate hash filter")]
+    DuplicateHash,
+    #[error("invalid content hash")]
+    InvalidHash,
+    #[error("unknown or duplicate status filter")]
+    InvalidStatus,
+    #[error("missing or duplicate sort field")]
+    InvalidSort,
+    #[error("limit must be a single number")]
+    InvalidLimit,
 }
 
 impl<'a> TryFrom<&'a str> for Query {
@@ -48,11 +225,48 @@ impl<'a> TryFrom<&'a str> for Query {
 
         for capture in query.split_whitespace() {
             if let Some(id) = capture.strip_prefix('@') {
-                if result.id.is_none() {
-                    result.id = Some(id.into());
-                } else {
+                if result.id.is_some() || result.parent.is_some() {
                     return Err(QueryParseError::DuplicateID);
                 }
+                if let Some(parent) = id.strip_suffix("/**") {
+                    result.parent = Some(normalize_id(parent));
+                    result.recursive = true;
+                } else if let Some(parent) = id.strip_suffix("/*") {
+                    result.parent = Some(normalize_id(parent));
+                } else {
+                    result.id = Some(normalize_id(id));
+                }
+            } else if let Some(hash) = capture.strip_prefix("hash:") {
+                if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(QueryParseError::InvalidHash);
+                } else if result.hash.is_some() {
+                    return Err(QueryParseError::DuplicateHash);
+                }
+                result.hash = Some(hash.to_ascii_lowercase());
+            } else if let Some(status) = capture.strip_prefix("status:") {
+                if result.status.is_some() {
+                    return Err(QueryParseError::InvalidStatus);
+                }
+                result.status = Some(Status::parse(status).ok_or(QueryParseError::InvalidStatus)?);
+            } else if let Some(tag) = capture.strip_prefix("tag:") {
+                result.tags.push(tag.into());
+            } else if let Some(field) = capture.strip_prefix("sort:") {
+                let (field, descending) = match field.strip_prefix('-') {
+                    Some(field) => (field, true),
+                    None => (field, false),
+                };
+                if field.is_empty() || result.sort.is_some() {
+                    return Err(QueryParseError::InvalidSort);
+                }
+                result.sort = Some(Sort {
+                    field: field.to_string(),
+                    descending,
+                });
+            } else if let Some(limit) = capture.strip_prefix("limit:") {
+                if result.limit.is_some() {
+                    return Err(QueryParseError::InvalidLimit);
+                }
+                result.limit = Some(limit.parse().map_err(|_| QueryParseError::InvalidLimit)?);
             } else if let Some(pos) = capture.find(operators) {
                 let (field, operator_and_arg) = capture.split_at(pos);
 
@@ -63,6 +277,17 @@ impl<'a> TryFrom<&'a str> for Query {
                 }
 
                 let (operator, argument) = operator_and_arg.split_at(1);
+                let link = match field {
+                    LinkFilter::ANY => Some(None),
+                    field => LinkKind::parse(field).map(Some),
+                };
+                if let (Some(kind), "=", Some(id)) = (link, operator, argument.strip_prefix('@')) {
+                    result.links.push(LinkFilter {
+                        kind,
+                        ids: vec![normalize_id(id)],
+                    });
+                    continue;
+                }
                 result.properties.push(PropertyFilter {
                     field: field.to_string(),
                     operator: match operator {
@@ -83,6 +308,7 @@ impl<'a> TryFrom<&'a str> for Query {
 
 #[cfg(test)]
 mod tests {
+    use crate::articles::NewArticleRequest;
     use crate::query::*;
     use std::convert::TryInto;
 
@@ -91,6 +317,21 @@ mod tests {
         let mut query: Query = "@index".try_into().unwrap();
         assert_eq!(query.id, Some("index".to_string()));
 
+        query = "@cafe\u{301}".try_into().unwrap();
+        assert_eq!(query.id, Some("café".to_string()));
+
+        query = "@blog/2023/hello".try_into().unwrap();
+        assert_eq!(query.id, Some("blog/2023/hello".to_string()));
+
+        query = "@blog/*".try_into().unwrap();
+        assert_eq!(query.id, None);
+        assert_eq!(query.parent, Some("blog".to_string()));
+        assert!(!query.recursive);
+
+        query = "@blog/**".try_into().unwrap();
+        assert_eq!(query.parent, Some("blog".to_string()));
+        assert!(query.recursive);
+
         query = "tag".try_into().unwrap();
         assert_eq!(query.tags, vec!["tag".to_string()]);
 
@@ -105,6 +346,117 @@ mod tests {
                 operator: PropertyOperator::Equals("1".to_string()),
             }]
         );
+
+        query = "parent=@docs links=@faq parent=docs".try_into().unwrap();
+        assert_eq!(
+            query.links,
+            vec![
+                LinkFilter {
+                    kind: Some(LinkKind::Parent),
+                    ids: vec!["docs".to_string()],
+                },
+                LinkFilter {
+                    kind: None,
+                    ids: vec!["faq".to_string()],
+                },
+            ]
+        );
+        // Without an @ it's an ordinary property
+        assert_eq!(query.properties.len(), 1);
+
+        query = "hash:AB12 tag".try_into().unwrap();
+        assert_eq!(query.hash, Some("ab12".to_string()));
+        assert_eq!(query.tags, vec!["tag".to_string()]);
+
+        query = "tag:news sort:-epoch limit:10".try_into().unwrap();
+        assert_eq!(query.tags, vec!["news".to_string()]);
+        assert_eq!(
+            query.sort,
+            Some(Sort {
+                field: "epoch".to_string(),
+                descending: true,
+            })
+        );
+        assert_eq!(query.limit, Some(10));
+    }
+
+    #[test]
+    fn test_query_arrange() {
+        let articles: Vec<Article> = [("a", "price:3"), ("b", ""), ("c", "price:20")]
+            .into_iter()
+            .map(|(id, properties)| {
+                Article::new(&NewArticleRequest {
+                    id: id.to_string(),
+                    properties: properties.to_string(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        let arrange = |query: &str| {
+            let query: Query = query.try_into().unwrap();
+            let mut arranged = articles.clone();
+            query.arrange(&mut arranged, |article| article);
+            arranged.into_iter().map(|a| a.id).collect::<Vec<_>>()
+        };
+
+        assert_eq!(arrange(""), ["a", "b", "c"]);
+        // Numerically, with articles missing the field last
+        assert_eq!(arrange("sort:price"), ["a", "c", "b"]);
+        assert_eq!(arrange("sort:-price"), ["c", "a", "b"]);
+        assert_eq!(arrange("sort:-id limit:2"), ["c", "b"]);
+        assert_eq!(arrange("limit:0"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_query_matches() {
+        let mut article = Article::new(&NewArticleRequest {
+            id: "shoe".to_string(),
+            body: "shoe".to_string(),
+            properties: "price:25 colour:red".to_string(),
+            tags: "product sale".to_string(),
+            ..Default::default()
+        });
+        let matches = |query: &str, article: &Article| {
+            let query: Query = query.try_into().unwrap();
+            query.matches(article)
+        };
+
+        assert!(ALL.matches(&article));
+        assert!(matches("@shoe product", &article));
+        assert!(!matches("@boot", &article));
+        assert!(matches("product sale", &article));
+        assert!(!matches("product archived", &article));
+        assert!(matches("price>9 price<100 colour=red", &article));
+        assert!(!matches("price>100", &article));
+        assert!(!matches("size=10", &article));
+
+        let hash = article.hash.clone();
+        assert!(matches(&format!("hash:{}", &hash[..6]), &article));
+        article.hash = "0".repeat(64);
+        assert!(!matches(&format!("hash:{}", &hash[..6]), &article));
+
+        assert!(matches("status:published", &article));
+        assert!(!matches("status:draft", &article));
+        article.status = Status::Draft;
+        assert!(matches("status:draft product", &article));
+
+        let linked = Article::new(&NewArticleRequest {
+            id: "install".to_string(),
+            links: "parent:docs related:faq related:setup".to_string(),
+            ..Default::default()
+        });
+        assert!(matches("parent=@docs", &linked));
+        assert!(!matches("parent=@faq", &linked));
+        assert!(matches("related=@setup links=@docs", &linked));
+        assert!(!matches("next=@docs", &linked));
+        assert!(!matches("links=@docs", &article));
+
+        article.id = "blog/2023/hello".to_string();
+        assert!(matches("@blog/2023/*", &article));
+        assert!(!matches("@blog/*", &article));
+        assert!(matches("@blog/**", &article));
+        assert!(!matches("@blo/**", &article));
+        assert!(!matches("@blog/2023/hello/*", &article));
     }
 
     #[test]
@@ -113,6 +465,9 @@ mod tests {
         assert!(query.is_err());
         assert_eq!(query.unwrap_err(), QueryParseError::DuplicateID);
 
+        query = "@blog/* @index".try_into();
+        assert_eq!(query.unwrap_err(), QueryParseError::DuplicateID);
+
         query = "count=".try_into();
         assert!(query.is_err());
         assert_eq!(query.unwrap_err(), QueryParseError::MissingOperatorField);
@@ -120,5 +475,23 @@ mod tests {
         query = "=".try_into();
         assert!(query.is_err());
         assert_eq!(query.unwrap_err(), QueryParseError::MissingOperatorArgument);
+
+        query = "hash:xyz".try_into();
+        assert_eq!(query.unwrap_err(), QueryParseError::InvalidHash);
+
+        query = "hash:ab hash:cd".try_into();
+        assert_eq!(query.unwrap_err(), QueryParseError::DuplicateHash);
+
+        query = "status:hidden".try_into();
+        assert_eq!(query.unwrap_err(), QueryParseError::InvalidStatus);
+
+        query = "sort:- ".try_into();
+        assert_eq!(query.unwrap_err(), QueryParseError::InvalidSort);
+
+        query = "sort:a sort:b".try_into();
+        assert_eq!(query.unwrap_err(), QueryParseError::InvalidSort);
+
+        query = "limit:ten".try_into();
+        assert_eq!(query.unwrap_err(), QueryParseError::InvalidLimit);
     }
 }
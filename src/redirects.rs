@@ -0,0 +1,146 @@
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+use serde_derive::Serialize;
+
+use crate::articles::{normalize_id, validate_id, Article};
+use crate::index::{self, Index};
+use crate::query::{self, Query};
+
+// Redirects are the aliases of every article, a request for an alias is
+// redirected to the id of its article
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Redirect {
+    pub from: String,
+    pub to: String,
+}
+
+pub fn list(index: &mut dyn Index) -> Result<Vec<Redirect>> {
+    let mut redirects = vec![];
+    for entry in index.search(query::ALL)? {
+        let article = entry.article();
+        for alias in article.aliases.iter() {
+            redirects.push(Redirect {
+                from: alias.clone(),
+                to: article.id.clone(),
+            });
+        }
+    }
+    redirects.sort_by(|a, b| a.from.cmp(&b.from));
+    Ok(redirects)
+}
+
+// Finds the article an id or alias leads to, with its body so that it can be
+// saved again
+fn load(index: &mut dyn Index, id: &str) -> Result<Option<Article>> {
+    if validate_id(id).is_err() || id.is_empty() {
+        return Ok(None);
+    }
+    let query: Query = format!("@{}", id).as_str().try_into()?;
+    match index.first(&query) {
+        Ok(entry) => {
+            let mut article = entry.article();
+            article.body = entry.body_string()?;
+            Ok(Some(article))
+        }
+        Err(e) => match e.downcast_ref::<index::Error>() {
+            Some(index::Error::ArticleNotFound) => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+// Redirects from to the article to
+pub fn add(index: &mut dyn Index, from: &str, to: &str) -> Result<()> {
+    let mut article = match load(index, to)? {
+        Some(article) => article,
+        None => bail!("there is no article {}", to),
+    };
+    article.aliases.insert(normalize_id(from));
+    index.update(&article)?;
+    Ok(())
+}
+
+pub fn remove(index: &mut dyn Index, from: &str) -> Result<()> {
+    let from = normalize_id(from);
+    let mut article = match load(index, &from)? {
+        Some(article) if article.aliases.contains(&from) => article,
+        _ => bail!("there is no redirect from {}", from),
+    };
+    article.aliases.remove(&from);
+    index.update(&article)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::articles::NewArticleRequest;
+    use crate::index::local::Local;
+    use crate::redirects::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_redirects() {
+        let dir = TempDir::new("redirects_test").unwrap();
+        let mut index = Local::new(dir.path()).unwrap();
+        let mut article = Article::new(&NewArticleRequest {
+            id: "about".to_string(),
+            body: "about us".to_string(),
+            aliases: "about-us".to_string(),
+            ..Default::default()
+        });
+        index.update(&article).unwrap();
+
+        // Renaming keeps the old id as an alias
+        article.id = "company/about".to_string();
+        index.update(&article).unwrap();
+        let to = |index: &mut Local, id: &str| {
+            let query: Query = format!("@{}", id).as_str().try_into().unwrap();
+            index.first(&query).unwrap().article().id
+        };
+        assert_eq!(to(&mut index, "about"), "company/about");
+        assert_eq!(to(&mut index, "about-us"), "company/about");
+
+        add(&mut index, "team", "about").unwrap();
+        assert!(add(&mut index, "x", "missing").is_err());
+        assert_eq!(
+            list(&mut index).unwrap(),
+            [
+                Redirect {
+                    from: "about".to_string(),
+                    to: "company/about".to_string()
+                },
+                Redirect {
+                    from: "about-us".to_string(),
+                    to: "company/about".to_string()
+                },
+                Redirect {
+                    from: "team".to_string(),
+                    to: "company/about".to_string()
+                },
+            ]
+        );
+        // Saving through a redirect keeps the body
+        let entry = index.first(&"@team".try_into().unwrap()).unwrap();
+        assert_eq!(entry.body_string().unwrap(), "about us");
+
+        // Aliases are ids, so can't be taken by another article
+        let taken = index.update(&Article::new(&NewArticleRequest {
+            id: "team".to_string(),
+            ..Default::default()
+        }));
+        assert_eq!(
+            taken.err().unwrap().downcast_ref::<index::Error>(),
+            Some(&index::Error::DuplicateId("team".to_string()))
+        );
+
+        remove(&mut index, "about-us").unwrap();
+        assert!(remove(&mut index, "company/about").is_err());
+        assert!(index
+            .search(&"@about-us".try_into().unwrap())
+            .unwrap()
+            .next()
+            .is_none());
+        assert_eq!(list(&mut index).unwrap().len(), 2);
+    }
+}
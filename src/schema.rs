@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use regex::Regex;
+
+use crate::articles::{Article, PropertySet};
+use crate::index::{self, Index};
+use crate::query::Query;
+use crate::validation::ValidationErrors;
+
+// Schemas are declared in config articles with an id of schema.<tag>, whose
+// body maps property names to types, a trailing ? makes a property optional:
+//
+//   price: decimal
+//   sku: string
+//   stock: int?
+pub const SCHEMA_ID_PREFIX: &str = "schema.";
+
+static DECIMAL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^-?[0-9]+(\.[0-9]+)?$").unwrap());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PropertyType {
+    String,
+    Int,
+    Decimal,
+    Bool,
+    Date,
+}
+
+impl PropertyType {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "string" => Some(PropertyType::String),
+            "int" => Some(PropertyType::Int),
+            "decimal" => Some(PropertyType::Decimal),
+            "bool" => Some(PropertyType::Bool),
+            "date" => Some(PropertyType::Date),
+            _ => None,
+        }
+    }
+
+    fn check(&self, value: &str) -> Result<(), &'static str> {
+        match self {
+            PropertyType::String => Ok(()),
+            PropertyType::Int if value.parse::<i64>().is_ok() => Ok(()),
+            PropertyType::Int => Err("must be a whole number"),
+            PropertyType::Decimal if DECIMAL.is_match(value) => Ok(()),
+            PropertyType::Decimal => Err("must be a decimal number"),
+            PropertyType::Bool if value == "true" || value == "false" => Ok(()),
+            PropertyType::Bool => Err("must be true or false"),
+            PropertyType::Date if NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok() => Ok(()),
+            PropertyType::Date => Err("must be a date formatted as YYYY-MM-DD"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PropertySpec {
+    pub kind: PropertyType,
+    pub optional: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schema {
+    pub tag: String,
+    pub properties: BTreeMap<String, PropertySpec>,
+}
+
+impl Schema {
+    // Parses a schema body, problems are reported against the body field
+    pub fn parse(tag: &str, body: &str) -> Result<Schema, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let declared: BTreeMap<String, String> = if body.trim().is_empty() {
+            BTreeMap::new()
+        } else {
+            match serde_yaml::from_str(body) {
+                Ok(declared) => declared,
+                Err(e) => {
+                    errors.add(
+                        "body",
+                        format!("schema is not a map of property types: {}", e),
+                    );
+                    return Err(errors);
+                }
+            }
+        };
+
+        let mut schema = Schema {
+            tag: tag.to_string(),
+            properties: BTreeMap::new(),
+        };
+        for (name, kind) in declared {
+            let (kind, optional) = match kind.trim().strip_suffix('?') {
+                Some(kind) => (kind, true),
+                None => (kind.trim(), false),
+            };
+            match PropertyType::parse(kind) {
+                Some(kind) => {
+                    schema
+                        .properties
+                        .insert(name, PropertySpec { kind, optional });
+                }
+                None => errors.add("body", format!("unknown type {} for {}", kind, name)),
+            }
+        }
+        errors.into_result().map(|_| schema)
+    }
+
+    pub fn validate(&self, properties: &PropertySet, errors: &mut ValidationErrors) {
+        for (name, spec) in self.properties.iter() {
+            let field = format!("properties.{}", name);
+            match properties.get(name) {
+                None if spec.optional => {}
+                None => errors.add(field, format!("is required for {} articles", self.tag)),
+                Some(value) => {
+                    if let Err(message) = spec.kind.check(value) {
+                        errors.add(field, message);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Loads the schemas declared for any of the tags
+pub fn schemas_for(index: &mut dyn Index, tags: &HashSet<String>) -> Result<Vec<Schema>> {
+    let mut schemas = vec![];
+    let mut tags: Vec<&String> = tags.iter().collect();
+    tags.sort();
+    for tag in tags {
+        let query: Query = format!("@{}{}", SCHEMA_ID_PREFIX, tag)
+            .as_str()
+            .try_into()?;
+        let entry = match index.first(&query) {
+            Ok(entry) => entry,
+            Err(e) => match e.downcast_ref::<index::Error>() {
+                Some(index::Error::ArticleNotFound) => continue,
+                _ => return Err(e),
+            },
+        };
+        // A broken schema was rejected when saved, but could be on disk from
+        // before, so treat it as declaring nothing
+        if let Ok(schema) = Schema::parse(tag, &entry.body_string()?) {
+            schemas.push(schema);
+        }
+    }
+    Ok(schemas)
+}
+
+// Validates an article against the schemas of its tags. Schema articles
+// themselves are checked to hold a valid schema.
+pub fn validate(index: &mut dyn Index, article: &Article) -> Result<()> {
+    let mut errors = ValidationErrors::default();
+    if let Some(tag) = article.id.strip_prefix(SCHEMA_ID_PREFIX) {
+        if let Err(schema_errors) = Schema::parse(tag, &article.body) {
+            errors = schema_errors;
+        }
+    }
+    for schema in schemas_for(index, &article.tags)? {
+        schema.validate(&article.properties, &mut errors);
+    }
+    Ok(errors.into_result()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::articles::NewArticleRequest;
+    use crate::index::local::Local;
+    use crate::schema::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse() {
+        let schema =
+            Schema::parse("product", "price: decimal\nsku: string\nstock: int?\n").unwrap();
+        assert_eq!(
+            schema.properties["stock"],
+            PropertySpec {
+                kind: PropertyType::Int,
+                optional: true
+            }
+        );
+        assert!(!schema.properties["price"].optional);
+        assert!(Schema::parse("product", "").unwrap().properties.is_empty());
+
+        let errors = Schema::parse("product", "price: money").unwrap_err();
+        assert_eq!(errors.fields["body"], ["unknown type money for price"]);
+        assert!(Schema::parse("product", "- price").is_err());
+    }
+
+    #[test]
+    fn test_validate_properties() {
+        let schema = Schema::parse("product", "price: decimal\nsku: string\nstock: int?").unwrap();
+        let mut properties = PropertySet::new();
+        properties.insert("price".into(), "9.99".into());
+        properties.insert("sku".into(), "A-1".into());
+
+        let mut errors = ValidationErrors::default();
+        schema.validate(&properties, &mut errors);
+        assert!(errors.is_empty());
+
+        properties.insert("price".into(), "cheap".into());
+        properties.insert("stock".into(), "1.5".into());
+        properties.remove("sku");
+        schema.validate(&properties, &mut errors);
+        assert_eq!(
+            errors.fields["properties.price"],
+            ["must be a decimal number"]
+        );
+        assert_eq!(
+            errors.fields["properties.stock"],
+            ["must be a whole number"]
+        );
+        assert_eq!(
+            errors.fields["properties.sku"],
+            ["is required for product articles"]
+        );
+    }
+
+    #[test]
+    fn test_index_validates() {
+        let dir = TempDir::new("schema_index_test").unwrap();
+        let mut index = Local::new(dir.path()).unwrap();
+
+        let invalid_schema = index.update(&Article::new(&NewArticleRequest {
+            id: "schema.product".to_string(),
+            body: "price: money".to_string(),
+            ..Default::default()
+        }));
+        assert!(invalid_schema.is_err());
+        index
+            .update(&Article::new(&NewArticleRequest {
+                id: "schema.product".to_string(),
+                body: "price: decimal\nstock: int?".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        let result = index.update(&Article::new(&NewArticleRequest {
+            id: "shoe".to_string(),
+            tags: "product".to_string(),
+            properties: "stock:lots".to_string(),
+            ..Default::default()
+        }));
+        let errors = result.err().unwrap();
+        let errors = errors.downcast_ref::<ValidationErrors>().unwrap();
+        assert_eq!(errors.fields.len(), 2);
+        assert!(errors.fields.contains_key("properties.price"));
+
+        index
+            .update(&Article::new(&NewArticleRequest {
+                id: "shoe".to_string(),
+                tags: "product".to_string(),
+                properties: "price:25.00".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+        // Untagged articles are not checked
+        index
+            .update(&Article::new(&NewArticleRequest {
+                id: "page".to_string(),
+                properties: "price:free".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_schema_sharing_prefix() {
+        let dir = TempDir::new("schema_prefix_test").unwrap();
+        let mut index = Local::new(dir.path()).unwrap();
+        for (id, body) in [
+            ("schema", ""),
+            ("schema.book", "pages: int"),
+            ("schema.booklet", "pages: int?"),
+            ("schema.zine", "pages: int?"),
+        ] {
+            index
+                .update(&Article::new(&NewArticleRequest {
+                    id: id.to_string(),
+                    body: body.to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+
+        let result = index.update(&Article::new(&NewArticleRequest {
+            id: "novel".to_string(),
+            tags: "book".to_string(),
+            properties: "pages:many".to_string(),
+            ..Default::default()
+        }));
+        let errors = result.err().unwrap();
+        let errors = errors.downcast_ref::<ValidationErrors>().unwrap();
+        assert_eq!(
+            errors.fields["properties.pages"],
+            ["must be a whole number"]
+        );
+    }
+}
@@ -0,0 +1,890 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::{bail, Result};
+use chrono_tz::Tz;
+use handlebars::{no_escape, Handlebars, RenderError, StringOutput};
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::content::RawHtml;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use walkdir::WalkDir;
+
+use crate::articles::{Article, Visibility};
+use crate::auth::Credentials;
+use crate::clock::{Clock, SystemClock};
+use crate::error;
+use crate::format::Sanitize;
+use crate::index::{local::Local, Index};
+use crate::layouts::render_with_layout;
+use crate::templates::{register_helpers, RequestInfo, RequestScope, ShowDrafts};
+
+// Paths to the directories that make up a site
+#[derive(Clone, Debug, Deserialize)]
+pub struct Paths {
+    pub data: PathBuf,
+    pub templates: PathBuf,
+    #[serde(rename = "static")]
+    pub statics: PathBuf,
+}
+
+// SiteConfig is the configuration of one site: where its files live and how
+// its index behaves
+#[derive(Clone, Debug, Deserialize)]
+pub struct SiteConfig {
+    #[serde(flatten)]
+    pub paths: Paths,
+    // Treat ids differing only in case as the same article
+    #[serde(default)]
+    pub case_insensitive_ids: bool,
+    // Which article bodies are cleaned of unsafe HTML
+    #[serde(default)]
+    pub sanitize: Sanitize,
+    // Without credentials the admin pages are open to everyone
+    #[serde(default)]
+    pub admin: Option<Credentials>,
+    // The time zone of the year, month and day properties of articles
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+    // Widths images may be resized to besides images::DEFAULT_WIDTHS
+    #[serde(default)]
+    pub image_widths: Vec<u32>,
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+impl From<Paths> for SiteConfig {
+    fn from(paths: Paths) -> Self {
+        SiteConfig {
+            paths,
+            case_insensitive_ids: false,
+            sanitize: Sanitize::default(),
+            admin: None,
+            timezone: default_timezone(),
+            image_widths: vec![],
+        }
+    }
+}
+
+// App holds everything needed to serve a single site: its index and the
+// templates rendered with helpers bound to that index
+pub struct App {
+    pub index: Arc<Mutex<Box<dyn Index>>>,
+    pub paths: Paths,
+    pub sanitize: Sanitize,
+    pub admin: Option<Credentials>,
+    pub timezone: Tz,
+    pub image_widths: Vec<u32>,
+    pub clock: Arc<dyn Clock>,
+    // Swapped whole when the templates are reloaded, renders already under
+    // way finish with the templates they started with
+    handlebars: RwLock<Arc<Handlebars<'static>>>,
+    // The same templates and helpers without HTML escaping, for expanding
+    // text bodies which are escaped as a whole afterwards
+    text_handlebars: RwLock<Arc<Handlebars<'static>>>,
+}
+
+impl App {
+    pub fn new<C: Into<SiteConfig>>(config: C) -> Result<Arc<App>> {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    // Tests pass a clock of their own to control the time
+    pub fn with_clock<C: Into<SiteConfig>>(config: C, clock: Arc<dyn Clock>) -> Result<Arc<App>> {
+        let SiteConfig {
+            paths,
+            case_insensitive_ids,
+            sanitize,
+            admin,
+            timezone,
+            image_widths,
+        } = config.into();
+        let index = Local::new(&paths.data)?
+            .case_insensitive_ids(case_insensitive_ids)
+            .clock(clock.clone());
+        let mut handlebars = Handlebars::new();
+        // Pick up template edits without a restart while developing
+        handlebars.set_dev_mode(cfg!(debug_assertions));
+        register_templates(&mut handlebars, &paths.templates)?;
+
+        // Helpers only hold a weak reference back to the app that owns them
+        Ok(Arc::new_cyclic(|app| {
+            register_helpers(&mut handlebars, app.clone());
+            let text_handlebars = text_registry(&handlebars);
+            App {
+                index: Arc::new(Mutex::new(Box::new(index))),
+                paths,
+                sanitize,
+                admin,
+                timezone,
+                image_widths,
+                clock,
+                handlebars: RwLock::new(Arc::new(handlebars)),
+                text_handlebars: RwLock::new(Arc::new(text_handlebars)),
+            }
+        }))
+    }
+
+    pub fn render<T: Serialize>(
+        &self,
+        name: &str,
+        ctx: &T,
+    ) -> Result<RawHtml<String>, error::Error> {
+        Ok(RawHtml(self.handlebars().render(name, ctx)?))
+    }
+
+    fn handlebars(&self) -> Arc<Handlebars<'static>> {
+        self.handlebars.read().unwrap().clone()
+    }
+
+    pub fn text_handlebars(&self) -> Arc<Handlebars<'static>> {
+        self.text_handlebars.read().unwrap().clone()
+    }
+
+    // Registers the templates again from disk, such as after a restore put
+    // other templates in place. The helpers stay as they are.
+    pub fn reload_templates(&self) -> Result<()> {
+        let mut handlebars = Handlebars::clone(&self.handlebars());
+        handlebars.clear_templates();
+        register_templates(&mut handlebars, &self.paths.templates)?;
+        *self.text_handlebars.write().unwrap() = Arc::new(text_registry(&handlebars));
+        *self.handlebars.write().unwrap() = Arc::new(handlebars);
+        Ok(())
+    }
+
+    // The articles that may be seen now, drafts included for previews
+    pub fn visibility(&self, drafts: bool) -> Visibility {
+        match drafts {
+            true => Visibility::Drafts,
+            false => Visibility::PublicAt(self.clock.now()),
+        }
+    }
+
+    // Renders a template in which articles that aren't published yet are
+    // shown as well, for administrators
+    pub fn preview<T: Serialize>(
+        &self,
+        name: &str,
+        ctx: &T,
+    ) -> Result<RawHtml<String>, error::Error> {
+        let _drafts = ShowDrafts::start();
+        self.render(name, ctx)
+    }
+
+    // Renders the body of an article as a page, with its metadata as the
+    // context and wrapped in its layout
+    pub fn render_article(
+        &self,
+        article: &Article,
+        body: String,
+    ) -> Result<RawHtml<String>, error::Error> {
+        let mut out = StringOutput::new();
+        render_with_layout(
+            &self.handlebars(),
+            self,
+            Some(article.clone()),
+            body,
+            &Value::Null,
+            &mut out,
+        )?;
+        Ok(RawHtml(out.into_string().map_err(RenderError::from)?))
+    }
+
+    pub fn preview_article(
+        &self,
+        article: &Article,
+        body: String,
+    ) -> Result<RawHtml<String>, error::Error> {
+        let _drafts = ShowDrafts::start();
+        self.render_article(article, body)
+    }
+}
+
+fn text_registry(handlebars: &Handlebars<'static>) -> Handlebars<'static> {
+    let mut text = handlebars.clone();
+    text.register_escape_fn(no_escape);
+    text
+}
+
+// Registers every template below dir named after its path without the
+// extension, so templates/articles/index.html.hbs becomes articles/index
+fn register_templates(handlebars: &mut Handlebars, dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        bail!("template directory {:?} does not exist", dir);
+    }
+    for entry in WalkDir::new(dir).sort_by_file_name().min_depth(1) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(dir)?.to_string_lossy();
+        if let Some(name) = relative
+            .strip_suffix(".html.hbs")
+            .or_else(|| relative.strip_suffix(".hbs"))
+        {
+            handlebars.register_template_file(name, entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default = "default_data_dir")]
+    data_dir: PathBuf,
+    #[serde(default = "default_template_dir")]
+    template_dir: PathBuf,
+    #[serde(default = "default_static_dir")]
+    static_dir: PathBuf,
+    #[serde(default)]
+    case_insensitive_ids: bool,
+    #[serde(default)]
+    sanitize: Sanitize,
+    #[serde(default)]
+    admin: Option<Credentials>,
+    #[serde(default = "default_timezone")]
+    timezone: Tz,
+    #[serde(default)]
+    image_widths: Vec<u32>,
+    #[serde(default)]
+    sites: HashMap<String, SiteConfig>,
+}
+
+fn default_data_dir() -> PathBuf {
+    "data".into()
+}
+
+fn default_template_dir() -> PathBuf {
+    "templates".into()
+}
+
+fn default_static_dir() -> PathBuf {
+    "site".into()
+}
+
+// Sites maps the Host header of a request to the App serving it. Without any
+// configured sites a single default site answers every host, otherwise only
+// the configured hosts are served.
+pub struct Sites {
+    hosts: HashMap<String, Arc<App>>,
+    default: Option<Arc<App>>,
+}
+
+impl Sites {
+    pub fn new(default: Option<SiteConfig>, hosts: HashMap<String, SiteConfig>) -> Result<Self> {
+        Ok(Sites {
+            hosts: hosts
+                .into_iter()
+                .map(|(host, config)| Ok((host.to_ascii_lowercase(), App::new(config)?)))
+                .collect::<Result<_>>()?,
+            default: default.map(App::new).transpose()?,
+        })
+    }
+
+    pub fn from_figment(figment: &Figment) -> Result<Self> {
+        let config: Config = figment.extract()?;
+        if config.sites.is_empty() {
+            let site = SiteConfig {
+                paths: Paths {
+                    data: config.data_dir,
+                    templates: config.template_dir,
+                    statics: config.static_dir,
+                },
+                case_insensitive_ids: config.case_insensitive_ids,
+                sanitize: config.sanitize,
+                admin: config.admin,
+                timezone: config.timezone,
+                image_widths: config.image_widths,
+            };
+            Self::new(Some(site), HashMap::new())
+        } else {
+            Self::new(None, config.sites)
+        }
+    }
+
+    pub fn select(&self, host: Option<&str>) -> Option<Arc<App>> {
+        host.and_then(|host| self.hosts.get(&host.to_ascii_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+// Site is a request guard resolving the App for the requested host. Pages
+// rendered through it let article bodies see the request.
+pub struct Site(Arc<App>, RequestInfo);
+
+impl Site {
+    pub fn request(&self) -> &RequestInfo {
+        &self.1
+    }
+
+    pub fn render<T: Serialize>(
+        &self,
+        name: &str,
+        ctx: &T,
+    ) -> Result<RawHtml<String>, error::Error> {
+        let _request = RequestScope::start(self.1.clone());
+        self.0.render(name, ctx)
+    }
+
+    pub fn preview<T: Serialize>(
+        &self,
+        name: &str,
+        ctx: &T,
+    ) -> Result<RawHtml<String>, error::Error> {
+        let _request = RequestScope::start(self.1.clone());
+        self.0.preview(name, ctx)
+    }
+
+    pub fn render_article(
+        &self,
+        article: &Article,
+        body: String,
+    ) -> Result<RawHtml<String>, error::Error> {
+        let _request = RequestScope::start(self.1.clone());
+        self.0.render_article(article, body)
+    }
+
+    pub fn preview_article(
+        &self,
+        article: &Article,
+        body: String,
+    ) -> Result<RawHtml<String>, error::Error> {
+        let _request = RequestScope::start(self.1.clone());
+        self.0.preview_article(article, body)
+    }
+}
+
+impl Deref for Site {
+    type Target = Arc<App>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Site {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let sites = request
+            .rocket()
+            .state::<Sites>()
+            .expect("sites are loaded on ignite");
+        let host = request.host().map(|host| host.domain().to_string());
+        let info = RequestInfo {
+            host: host.clone().unwrap_or_default(),
+            path: request.uri().path().to_string(),
+            query: request
+                .uri()
+                .query()
+                .map(|query| {
+                    query
+                        .segments()
+                        .map(|(name, value)| (name.to_string(), value.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        match sites.select(host.as_deref()) {
+            Some(app) => Outcome::Success(Site(app, info)),
+            None => Outcome::Error((Status::NotFound, ())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::fs::{self, create_dir_all};
+
+    use crate::articles::{Article, NewArticleRequest};
+    use crate::clock::FixedClock;
+    use crate::query;
+    use crate::site::*;
+    use chrono::Duration;
+    use tempdir::TempDir;
+
+    fn paths(root: &Path) -> SiteConfig {
+        let paths = Paths {
+            data: root.join("data"),
+            templates: root.join("templates"),
+            statics: root.join("site"),
+        };
+        create_dir_all(paths.templates.join("articles")).unwrap();
+        fs::write(paths.templates.join("index.html.hbs"), "index").unwrap();
+        fs::write(paths.templates.join("articles/index.html.hbs"), "list").unwrap();
+        paths.into()
+    }
+
+    #[test]
+    fn test_select() {
+        let first = TempDir::new("sites_first").unwrap();
+        let second = TempDir::new("sites_second").unwrap();
+        let sites = Sites::new(
+            None,
+            HashMap::from([
+                ("First.example".to_string(), paths(first.path())),
+                ("second.example".to_string(), paths(second.path())),
+            ]),
+        )
+        .unwrap();
+
+        let app = sites.select(Some("first.example")).unwrap();
+        assert_eq!(app.paths.data, first.path().join("data"));
+        assert!(sites.select(Some("SECOND.example")).is_some());
+        assert!(sites.select(Some("third.example")).is_none());
+        assert!(sites.select(None).is_none());
+
+        // Each site only sees its own articles
+        app.index
+            .lock()
+            .unwrap()
+            .update(&Article::new(&NewArticleRequest {
+                id: "main".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+        let other = sites.select(Some("second.example")).unwrap();
+        let mut index = other.index.lock().unwrap();
+        assert_eq!(0, index.search(query::ALL).unwrap().count());
+    }
+
+    #[test]
+    fn test_default_site() {
+        let root = TempDir::new("sites_default").unwrap();
+        let sites = Sites::new(Some(paths(root.path())), HashMap::new()).unwrap();
+        assert!(sites.select(Some("anything.example")).is_some());
+        assert!(sites.select(None).is_some());
+    }
+
+    #[test]
+    fn test_case_insensitive_ids() {
+        let root = TempDir::new("sites_case").unwrap();
+        let app = App::new(SiteConfig {
+            case_insensitive_ids: true,
+            ..paths(root.path())
+        })
+        .unwrap();
+        let mut index = app.index.lock().unwrap();
+        index
+            .update(&Article::new(&NewArticleRequest {
+                id: "Café".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+        let query = "@CAFÉ".try_into().unwrap();
+        assert_eq!(index.first(&query).unwrap().article().id, "Café");
+        assert!(index
+            .update(&Article::new(&NewArticleRequest {
+                id: "café".to_string(),
+                ..Default::default()
+            }))
+            .is_err());
+    }
+
+    #[test]
+    fn test_drafts() {
+        let root = TempDir::new("sites_drafts").unwrap();
+        let config = paths(root.path());
+        fs::write(
+            config.paths.templates.join("all.html.hbs"),
+            "{{ articles \"\" }}",
+        )
+        .unwrap();
+        let app = App::new(config).unwrap();
+        for (id, status) in [("public", ""), ("secret", "draft")] {
+            app.index
+                .lock()
+                .unwrap()
+                .update(&Article::new(&NewArticleRequest {
+                    id: id.to_string(),
+                    body: id.to_string(),
+                    status: status.to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+
+        assert_eq!(app.render("all", &()).unwrap().0, "public");
+        let preview = app.preview("all", &()).unwrap().0;
+        assert!(preview.contains("public") && preview.contains("secret"));
+        // The preview ends with its render
+        assert_eq!(app.render("all", &()).unwrap().0, "public");
+    }
+
+    #[test]
+    fn test_scheduled() {
+        let root = TempDir::new("sites_scheduled").unwrap();
+        let config = paths(root.path());
+        fs::write(
+            config.paths.templates.join("all.html.hbs"),
+            "{{ articles \"\" }}",
+        )
+        .unwrap();
+        let start = "2030-01-01T00:00:00Z".parse().unwrap();
+        let clock = Arc::new(FixedClock::new(start));
+        let app = App::with_clock(config, clock.clone()).unwrap();
+        let request = NewArticleRequest {
+            id: "soon".to_string(),
+            body: "soon".to_string(),
+            status: "scheduled".to_string(),
+            publish_at: "2030-01-02".to_string(),
+            ..Default::default()
+        };
+        let article = Article::new_at(&request, app.clock.now(), app.timezone);
+        app.index.lock().unwrap().update(&article).unwrap();
+
+        assert_eq!(app.render("all", &()).unwrap().0, "");
+        clock.advance(Duration::days(1));
+        assert_eq!(app.render("all", &()).unwrap().0, "soon");
+    }
+
+    #[test]
+    fn test_link_helpers() {
+        let root = TempDir::new("sites_links").unwrap();
+        let config = paths(root.path());
+        fs::write(
+            config.paths.templates.join("tree.html.hbs"),
+            "{{ children \"@docs\" }}|{{ backlinks \"@install\" }}",
+        )
+        .unwrap();
+        let app = App::new(config).unwrap();
+        for (id, links) in [
+            ("docs", ""),
+            ("install", "parent:docs"),
+            ("usage", "parent:docs prev:install"),
+        ] {
+            app.index
+                .lock()
+                .unwrap()
+                .update(&Article::new(&NewArticleRequest {
+                    id: id.to_string(),
+                    body: id.to_string(),
+                    links: links.to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        assert_eq!(app.render("tree", &()).unwrap().0, "installusage|usage");
+    }
+
+    #[test]
+    fn test_body_context() {
+        let root = TempDir::new("sites_context").unwrap();
+        let config = paths(root.path());
+        fs::write(
+            config.paths.templates.join("shoe.html.hbs"),
+            "{{ article \"@shoe\" }}",
+        )
+        .unwrap();
+        let app = App::new(config).unwrap();
+        app.index
+            .lock()
+            .unwrap()
+            .update(&Article::new(&NewArticleRequest {
+                id: "shoe".to_string(),
+                title: "Shoe".to_string(),
+                properties: "price:25 timestamp:2020-01-02T03:04:05Z".to_string(),
+                tags: "sale".to_string(),
+                body: "{{title}} {{id}} {{properties.price}} {{#each tags}}{{this}}{{/each}} \
+                       {{timestamp}} {{parent.greeting}} {{request.path}} {{request.query.page}}"
+                    .to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+        let _request = RequestScope::start(RequestInfo {
+            host: "example.com".to_string(),
+            path: "/shoe".to_string(),
+            query: [("page".to_string(), "2".to_string())].into(),
+        });
+        assert_eq!(
+            app.render("shoe", &serde_json::json!({"greeting": "hi"}))
+                .unwrap()
+                .0,
+            "Shoe shoe 25 sale 2020-01-02 03:04:05 UTC hi /shoe 2"
+        );
+    }
+
+    #[test]
+    fn test_text_body_escaped_once() {
+        let root = TempDir::new("sites_text").unwrap();
+        let app = App::new(paths(root.path())).unwrap();
+        let article = Article::new(&NewArticleRequest {
+            id: "note".to_string(),
+            title: "A & B".to_string(),
+            properties: "format:text brand:<A&B>".to_string(),
+            body: "{{title}} {{properties.brand}} & co".to_string(),
+            ..Default::default()
+        });
+        app.index.lock().unwrap().update(&article).unwrap();
+        assert_eq!(
+            app.render_article(&article, article.body.clone())
+                .unwrap()
+                .0,
+            "<pre>A &amp; B &lt;A&amp;B&gt; &amp; co</pre>"
+        );
+    }
+
+    #[test]
+    fn test_articles_block() {
+        let root = TempDir::new("sites_articles_block").unwrap();
+        let config = paths(root.path());
+        fs::write(
+            config.paths.templates.join("news.html.hbs"),
+            "{{#articles \"tag:news sort:-price limit:2\" as |a|}}\
+             {{@index}}{{#if @first}}F{{/if}}{{#if @last}}L{{/if}}:{{a.title}}={{body a}},{{body}};\
+             {{else}}none{{/articles}}|{{#articles \"tag:sport\"}}{{title}}{{else}}none{{/articles}}",
+        )
+        .unwrap();
+        let app = App::new(config).unwrap();
+        for (title, price) in [("Cheap", 1), ("Dear", 30), ("Middling", 12)] {
+            app.index
+                .lock()
+                .unwrap()
+                .update(&Article::new(&NewArticleRequest {
+                    id: title.to_lowercase(),
+                    title: title.to_string(),
+                    properties: format!("price:{}", price),
+                    tags: "news".to_string(),
+                    body: "<b>{{title}}</b>".to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        assert_eq!(
+            app.render("news", &()).unwrap().0,
+            "0F:Dear=<b>Dear</b>,<b>Dear</b>;1L:Middling=<b>Middling</b>,<b>Middling</b>;|none"
+        );
+    }
+
+    #[test]
+    fn test_paginate_helper() {
+        let root = TempDir::new("sites_paginate").unwrap();
+        let config = paths(root.path());
+        fs::write(
+            config.paths.templates.join("blog.html.hbs"),
+            "{{#paginate \"tag:news sort:title limit:2\" as |page|}}\
+             {{page.number}}/{{page.pages}} of {{total}}:\
+             {{#each page.items}}{{title}}={{body this}},{{/each}}\
+             {{#if page.next}} next{{/if}}{{#if page.prev}} prev{{/if}}\
+             {{#each page.numbers}} {{#if current}}[{{number}}]{{else}}{{number}}{{/if}}{{/each}}\
+             {{else}}empty{{/paginate}}",
+        )
+        .unwrap();
+        let app = App::new(config).unwrap();
+        for title in ["A", "B", "C", "D", "E"] {
+            app.index
+                .lock()
+                .unwrap()
+                .update(&Article::new(&NewArticleRequest {
+                    id: title.to_lowercase(),
+                    title: title.to_string(),
+                    tags: "news".to_string(),
+                    body: "{{id}}".to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        let render = |query: &[(&str, &str)]| {
+            let _request = RequestScope::start(RequestInfo {
+                path: "/blog".to_string(),
+                query: query
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                ..Default::default()
+            });
+            app.render("blog", &()).unwrap().0
+        };
+
+        assert_eq!(render(&[]), "1/3 of 5:A=a,B=b, next [1] 2 3");
+        assert_eq!(render(&[("page", "3")]), "3/3 of 5:E=e, prev 1 2 [3]");
+        assert_eq!(render(&[("page", "4")]), "empty");
+        let c = app
+            .index
+            .lock()
+            .unwrap()
+            .first(&"@c".try_into().unwrap())
+            .unwrap()
+            .article();
+        assert_eq!(
+            render(&[("after", &c.key.to_string())]),
+            "2/3 of 5:D=d,E=e, prev 1 [2] 3"
+        );
+    }
+
+    #[test]
+    fn test_layouts() {
+        let root = TempDir::new("sites_layouts").unwrap();
+        let config = paths(root.path());
+        for (name, template) in [
+            ("page", "{{ article \"@page\" }}"),
+            ("notes", "{{ article \"@notes\" }}"),
+            ("loop", "{{ article \"@loop\" }}"),
+            (
+                "shell",
+                "<title>{{#block \"title\"}}ota{{/block}}</title>{{#block \"content\"}}{{/block}}",
+            ),
+        ] {
+            fs::write(
+                config.paths.templates.join(format!("{}.html.hbs", name)),
+                template,
+            )
+            .unwrap();
+        }
+        let app = App::new(config).unwrap();
+        for (id, properties, body) in [
+            (
+                "section",
+                "layout:shell",
+                "{{#block \"title\"}}Section{{/block}}<main>{{#block \"content\"}}{{/block}}</main>",
+            ),
+            ("page", "layout:section", "{{#block \"title\"}}Page{{/block}}Hello"),
+            ("notes", "layout:section format:markdown", "*Hi*"),
+            ("loop", "layout:again", "x"),
+            ("again", "layout:loop", "y"),
+        ] {
+            app.index
+                .lock()
+                .unwrap()
+                .update(&Article::new(&NewArticleRequest {
+                    id: id.to_string(),
+                    body: body.to_string(),
+                    properties: properties.to_string(),
+                    ..Default::default()
+                }))
+                .unwrap();
+        }
+        // The innermost title wins, each layout places the content
+        assert_eq!(
+            app.render("page", &()).unwrap().0,
+            "<title>Page</title><main>Hello</main>"
+        );
+        assert_eq!(
+            app.render("notes", &()).unwrap().0,
+            "<title>Section</title><main><p><em>Hi</em></p>\n</main>"
+        );
+        assert!(app.render("loop", &()).is_err());
+    }
+
+    #[test]
+    fn test_render_article() {
+        let root = TempDir::new("sites_render_article").unwrap();
+        let app = App::new(paths(root.path())).unwrap();
+        let mut articles = vec![];
+        for (id, properties, body) in [
+            (
+                "shell",
+                "",
+                "<title>{{#block \"title\"}}{{/block}}</title>{{#block \"content\"}}{{/block}}",
+            ),
+            (
+                "hello",
+                "layout:shell colour:red",
+                "{{#block \"title\"}}{{title}}{{/block}}{{ properties.colour }} {{ hex 255 }}",
+            ),
+            ("broken", "", "{{ missing }}"),
+        ] {
+            let article = Article::new(&NewArticleRequest {
+                id: id.to_string(),
+                title: "Hello".to_string(),
+                body: body.to_string(),
+                properties: properties.to_string(),
+                ..Default::default()
+            });
+            app.index.lock().unwrap().update(&article).unwrap();
+            articles.push((article, body.to_string()));
+        }
+        // The body is a template, not the name of one
+        let (hello, body) = articles[1].clone();
+        assert_eq!(
+            app.render_article(&hello, body).unwrap().0,
+            "<title>Hello</title>red 0xff"
+        );
+        let (broken, body) = articles[2].clone();
+        assert!(app.render_article(&broken, body).is_err());
+    }
+
+    #[test]
+    fn test_srcset_helper() {
+        let root = TempDir::new("sites_srcset").unwrap();
+        let config = paths(root.path());
+        let hash = "ab".repeat(32);
+        fs::write(
+            config.paths.templates.join("image.html.hbs"),
+            format!(
+                "{{{{ srcset \"/media/{}/a.png\" widths=\"100 200\" fmt=\"webp\" alt=\"A & B\" }}}}",
+                hash
+            ),
+        )
+        .unwrap();
+        fs::write(
+            config.paths.templates.join("bad.html.hbs"),
+            "{{ srcset \"/media/../a.png\" }}",
+        )
+        .unwrap();
+        fs::write(
+            config.paths.templates.join("unknown.html.hbs"),
+            format!(
+                "{{{{ srcset \"/media/{}/a.png\" widths=\"100 300\" }}}}",
+                hash
+            ),
+        )
+        .unwrap();
+        let app = App::new(SiteConfig {
+            image_widths: vec![100, 200],
+            ..config
+        })
+        .unwrap();
+        let url = format!("/media/{}/a.png", hash);
+        assert_eq!(
+            app.render("image", &()).unwrap().0,
+            format!(
+                r#"<img src="{0}?w&#x3D;200&amp;fmt&#x3D;webp" srcset="{0}?w&#x3D;100&amp;fmt&#x3D;webp 100w, {0}?w&#x3D;200&amp;fmt&#x3D;webp 200w" alt="A &amp; B">"#,
+                url
+            )
+        );
+        assert!(app.render("bad", &()).is_err());
+        assert!(app.render("unknown", &()).is_err());
+    }
+
+    #[test]
+    fn test_templates() {
+        let root = TempDir::new("sites_templates").unwrap();
+        let config = paths(root.path());
+        fs::write(
+            config.paths.templates.join("search.html.hbs"),
+            "/articles?q=tag:{{url_encode tag}}",
+        )
+        .unwrap();
+        let app = App::new(config).unwrap();
+        assert_eq!(app.render("index", &()).unwrap().0, "index");
+        assert_eq!(
+            app.render("search", &serde_json::json!({"tag": "a&b c"}))
+                .unwrap()
+                .0,
+            "/articles?q=tag:a%26b%20c"
+        );
+        assert_eq!(app.render("articles/index", &()).unwrap().0, "list");
+        assert!(app.render("missing", &()).is_err());
+
+        // Templates put in place later, as by a restore, are picked up on
+        // reload and those that went are gone
+        fs::write(root.path().join("templates/missing.html.hbs"), "found").unwrap();
+        fs::remove_file(root.path().join("templates/articles/index.html.hbs")).unwrap();
+        app.reload_templates().unwrap();
+        assert_eq!(app.render("missing", &()).unwrap().0, "found");
+        assert!(app.render("articles/index", &()).is_err());
+        assert_eq!(app.render("index", &()).unwrap().0, "index");
+    }
+}
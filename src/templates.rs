@@ -1,27 +1,152 @@
 // use std::fs::File;
 // use std::io::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use std::convert::TryInto;
-use std::io::Read;
-use std::sync::Arc;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Weak};
 
 use handlebars::{
-    handlebars_helper, Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext,
-    RenderError,
+    handlebars_helper, to_json, BlockContext, BlockParams, Context, Handlebars, Helper, HelperDef,
+    HelperResult, Output, RenderContext, RenderError, Renderable,
 };
+use rocket::http::RawStr;
 // use serde::Serialize;
+use serde_derive::Serialize;
 use serde_json::value::Value;
 
-use crate::articles::lookup_article;
-use crate::query::{Query, QueryParseError};
+use crate::articles::{lookup_article, Article, LinkKind};
+use crate::format::{Format, Sanitize};
+use crate::images::{self, DEFAULT_WIDTHS};
+use crate::index::Entry;
+use crate::layouts::{block_helper, render_with_layout, BlockScope};
+use crate::media::is_hash;
+use crate::pagination::{find_page, PageContext, Position};
+use crate::query::{LinkFilter, Query, QueryParseError};
 
-use crate::App;
+use crate::site::App;
+
+thread_local! {
+    static DRAFTS: Cell<bool> = const { Cell::new(false) };
+    static REQUEST: RefCell<Option<RequestInfo>> = const { RefCell::new(None) };
+}
+
+// While a ShowDrafts is alive, templates rendered on the same thread show
+// articles that aren't public yet
+pub struct ShowDrafts(bool);
+
+impl ShowDrafts {
+    pub fn start() -> Self {
+        ShowDrafts(DRAFTS.with(|drafts| drafts.replace(true)))
+    }
+}
+
+impl Drop for ShowDrafts {
+    fn drop(&mut self) {
+        DRAFTS.with(|drafts| drafts.set(self.0));
+    }
+}
+
+pub fn show_drafts() -> bool {
+    DRAFTS.with(|drafts| drafts.get())
+}
+
+// The request being served, as article bodies see it
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RequestInfo {
+    pub host: String,
+    pub path: String,
+    pub query: BTreeMap<String, String>,
+}
+
+// While a RequestScope is alive, article bodies rendered on the same thread
+// see its request
+pub struct RequestScope(Option<RequestInfo>);
+
+impl RequestScope {
+    pub fn start(request: RequestInfo) -> Self {
+        RequestScope(REQUEST.with(|current| current.replace(Some(request))))
+    }
+}
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        REQUEST.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+pub fn current_request() -> RequestInfo {
+    REQUEST.with(|current| current.borrow().clone().unwrap_or_default())
+}
+
+// The metadata of an article as templates see it
+#[derive(Serialize)]
+pub struct ArticleContext<'a> {
+    #[serde(flatten)]
+    article: &'a Article,
+    timestamp: Option<&'a String>,
+    // The path of the article, ready to link to
+    url: String,
+}
+
+impl<'a> ArticleContext<'a> {
+    pub fn new(article: &'a Article) -> Self {
+        ArticleContext {
+            article,
+            timestamp: article.properties.get("timestamp"),
+            url: article.uri(),
+        }
+    }
+}
+
+// What an article body is rendered with: the metadata of its article, the
+// context of the template embedding it and the request, so that a body can
+// use {{title}}, {{properties.price}} or {{parent.flash}}
+#[derive(Serialize)]
+struct BodyContext<'a> {
+    #[serde(flatten)]
+    article: Option<ArticleContext<'a>>,
+    parent: &'a Value,
+    request: RequestInfo,
+}
+
+impl<'a> BodyContext<'a> {
+    fn new(article: Option<&'a Article>, parent: &'a Value) -> Self {
+        BodyContext {
+            article: article.map(ArticleContext::new),
+            parent,
+            request: current_request(),
+        }
+    }
+}
+
+fn upgrade(state: &Weak<App>) -> Result<Arc<App>, RenderError> {
+    state
+        .upgrade()
+        .ok_or_else(|| RenderError::new("site is no longer being served"))
+}
+
+// Adapts a handlebars Output so nested renders can stream straight into it
+// rather than building up an intermediate string
+struct OutputWriter<'a>(&'a mut dyn Output);
+
+impl<'a> Write for OutputWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(&String::from_utf8_lossy(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 // Provides a helper to embed an article in the current template
-fn wrapped_article_helper(state: Arc<App>) -> Box<dyn HelperDef + Sync + Send> {
+fn wrapped_article_helper(state: Weak<App>) -> Box<dyn HelperDef + Sync + Send> {
     Box::new(
         move |h: &Helper,
               handlebars: &Handlebars,
-              _: &Context,
+              context: &Context,
               _: &mut RenderContext,
               out: &mut dyn Output|
               -> HelperResult {
@@ -31,49 +156,358 @@ fn wrapped_article_helper(state: Arc<App>) -> Box<dyn HelperDef + Sync + Send> {
                 .ok_or_else(|| RenderError::new("requires an article query"))?;
             let mut buffer = String::new();
 
-            let mut index = state.index.lock().unwrap();
-            lookup_article(&mut index, query)
-                .unwrap()
-                .read_to_string(&mut buffer)
-                .unwrap();
+            let state = upgrade(&state)?;
+            let visibility = state.visibility(show_drafts());
+            let article = {
+                let mut index = state.index.lock().unwrap();
+                let (article, mut body) =
+                    lookup_article(&mut index, &state.paths.templates, query, visibility)
+                        .map_err(|e| RenderError::new(e.to_string()))?;
+                body.read_to_string(&mut buffer)?;
+                article
+            };
 
-            out.write(dbg!(handlebars.render_template(&buffer, &()))?.as_ref())?;
-            Ok(())
+            render_with_layout(handlebars, &state, article, buffer, context.data(), out)
         },
     )
 }
 
-// Articles returns all articles that match a pattern, can be used for pagination
-fn wrapped_articles_helper(state: Arc<App>) -> Box<dyn HelperDef + Sync + Send> {
+// Expands a body with Handlebars and converts it into HTML by its format.
+// parent is the context of the template the body is rendered in.
+pub fn render_body(
+    handlebars: &Handlebars,
+    state: &App,
+    article: Option<&Article>,
+    body: &str,
+    parent: &Value,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let format = Format::of(article);
+    let context = BodyContext::new(article, parent);
+    if format == Format::Html && state.sanitize != Sanitize::All {
+        handlebars.render_template_to_write(body, &context, OutputWriter(out))?;
+    } else {
+        let expanded = match format {
+            Format::Text => state.text_handlebars().render_template(body, &context)?,
+            _ => handlebars.render_template(body, &context)?,
+        };
+        out.write(&format.render(&expanded, state.sanitize))?;
+    }
+    Ok(())
+}
+
+// {{ articles "query" }} writes the bodies of the articles matching a query one
+// after another. As a block, {{#articles "tag:news sort:-epoch limit:10" as |a|}}
+// is rendered once per article with its metadata, @index, @first and @last,
+// leaving its body to {{ body a }}, and {{else}} is rendered when none match.
+struct ArticlesHelper {
+    state: Weak<App>,
+}
+
+impl HelperDef for ArticlesHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        handlebars: &'reg Handlebars<'reg>,
+        context: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let query: Query = h
+            .param(0)
+            .and_then(|v| v.value().as_str())
+            .ok_or_else(|| RenderError::new("requires an article query"))?
+            .try_into()
+            .map_err(|e: QueryParseError| RenderError::new(format!("query error: {}", e)))?;
+        let state = upgrade(&self.state)?;
+        let template = match h.template() {
+            Some(template) => template,
+            None => return render_articles(handlebars, &state, &query, context.data(), out),
+        };
+
+        let articles = find_articles(&state, &query)?;
+        if articles.is_empty() {
+            if let Some(inverse) = h.inverse() {
+                inverse.render(handlebars, context, rc, out)?;
+            }
+            return Ok(());
+        }
+        rc.push_block(BlockContext::new());
+        let last = articles.len() - 1;
+        for (i, (article, _)) in articles.iter().enumerate() {
+            let value = to_json(ArticleContext::new(article));
+            if let Some(block) = rc.block_mut() {
+                block.set_base_value(value.clone());
+                block.set_local_var("index", to_json(i));
+                block.set_local_var("first", to_json(i == 0));
+                block.set_local_var("last", to_json(i == last));
+                if let Some(name) = h.block_param() {
+                    let mut params = BlockParams::new();
+                    params.add_value(name, value)?;
+                    block.set_block_params(params);
+                }
+            }
+            template.render(handlebars, context, rc, out)?;
+        }
+        rc.pop_block();
+        Ok(())
+    }
+}
+
+// Matching articles along with their entries, to read their bodies from
+type Listing = Vec<(Article, Box<dyn Entry>)>;
+
+// The articles matching query that may be seen, in the order it asks for
+fn find_articles(state: &App, query: &Query) -> Result<Listing, RenderError> {
+    let entries = state
+        .index
+        .lock()
+        .unwrap()
+        .search(query)
+        .map_err(|e| RenderError::new(e.to_string()))?;
+    let visibility = state.visibility(show_drafts());
+    let mut articles: Vec<_> = entries
+        .map(|entry| (entry.article(), entry))
+        .filter(|(article, _)| visibility.shows(article))
+        .collect();
+    query.arrange(&mut articles, |(article, _)| article);
+    Ok(articles)
+}
+
+// Renders every article matching query that may be seen
+fn render_articles(
+    handlebars: &Handlebars,
+    state: &App,
+    query: &Query,
+    parent: &Value,
+    out: &mut dyn Output,
+) -> HelperResult {
+    // The entries are found up front so the index isn't locked while they
+    // render, as they may look up articles themselves. Each body is only read
+    // when its turn comes.
+    let articles = find_articles(state, query)?;
+    // Listed bodies are rendered without their layouts and keep their blocks
+    // to themselves
+    let _scope = BlockScope::start();
+    for (article, entry) in articles {
+        let body = entry
+            .body_string()
+            .map_err(|e| RenderError::new(e.to_string()))?;
+        render_body(handlebars, state, Some(&article), &body, parent, out)?;
+    }
+    Ok(())
+}
+
+// {{#paginate "tag:news limit:5" as |page|}} renders a page of the articles
+// matching a query, the one asked for by ?page=, ?after= or ?before= in the
+// request. The page has its items, total, number, pages and links to the
+// prev, next and numbered pages. {{else}} is rendered when the page is empty.
+struct PaginateHelper {
+    state: Weak<App>,
+}
+
+impl HelperDef for PaginateHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        handlebars: &'reg Handlebars<'reg>,
+        context: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let query: Query = h
+            .param(0)
+            .and_then(|v| v.value().as_str())
+            .ok_or_else(|| RenderError::new("requires an article query"))?
+            .try_into()
+            .map_err(|e: QueryParseError| RenderError::new(format!("query error: {}", e)))?;
+        let template = h
+            .template()
+            .ok_or_else(|| RenderError::new("paginate must be used as a block"))?;
+        let state = upgrade(&self.state)?;
+        let request = current_request();
+        let page = {
+            let mut index = state.index.lock().unwrap();
+            let visibility = state.visibility(show_drafts());
+            let position = Position::from_params(&request.query);
+            find_page(&mut **index, &query, visibility, position)
+                .map_err(|e| RenderError::new(e.to_string()))?
+        };
+        if page.items.is_empty() {
+            if let Some(inverse) = h.inverse() {
+                inverse.render(handlebars, context, rc, out)?;
+            }
+            return Ok(());
+        }
+
+        let page = page.map(|(article, _)| to_json(ArticleContext::new(&article)));
+        let value = to_json(PageContext::new(page, &request));
+        let mut block = BlockContext::new();
+        block.set_base_value(value.clone());
+        if let Some(name) = h.block_param() {
+            let mut params = BlockParams::new();
+            params.add_value(name, value)?;
+            block.set_block_params(params);
+        }
+        rc.push_block(block);
+        template.render(handlebars, context, rc, out)?;
+        rc.pop_block();
+        Ok(())
+    }
+}
+
+// {{ body a }} renders the body of an article listed by {{#articles}}, or of
+// the article being listed without a parameter. Bodies are only read and
+// rendered when a template asks for them.
+struct BodyHelper {
+    state: Weak<App>,
+}
+
+impl HelperDef for BodyHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        handlebars: &'reg Handlebars<'reg>,
+        context: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let value = match h.param(0) {
+            Some(param) => param.value().clone(),
+            None => rc.evaluate(context, "this")?.as_json().clone(),
+        };
+        let article: Article = serde_json::from_value(value)
+            .map_err(|_| RenderError::new("requires an article listed by articles"))?;
+        let state = upgrade(&self.state)?;
+        let mut body = String::new();
+        let content = state.index.lock().unwrap().content(&article.hash);
+        content
+            .map_err(|e| RenderError::new(e.to_string()))?
+            .read_to_string(&mut body)?;
+        let _scope = BlockScope::start();
+        render_body(
+            handlebars,
+            &state,
+            Some(&article),
+            &body,
+            context.data(),
+            out,
+        )
+    }
+}
+
+// Provides helpers listing the articles that link to one, such as
+// {{ children "@docs" }} for the articles whose parent is docs. kind is the
+// name of the link filter used, parent or links for links of any kind.
+fn wrapped_linked_helper(state: Weak<App>, kind: &'static str) -> Box<dyn HelperDef + Sync + Send> {
     Box::new(
         move |h: &Helper,
               handlebars: &Handlebars,
-              _: &Context,
+              context: &Context,
               _: &mut RenderContext,
               out: &mut dyn Output|
               -> HelperResult {
-            let query: Query = h
+            let id = h
                 .param(0)
-                .map(|v| v.value().as_str().unwrap())
-                .ok_or_else(|| RenderError::new("requires an article query"))?
+                .and_then(|v| v.value().as_str())
+                .and_then(|v| v.strip_prefix('@'))
+                .ok_or_else(|| RenderError::new("requires an article id such as @docs"))?;
+            let query: Query = format!("{}=@{}", kind, id)
+                .as_str()
                 .try_into()
                 .map_err(|_e: QueryParseError| RenderError::new("query error"))?;
+            render_articles(handlebars, &*upgrade(&state)?, &query, context.data(), out)
+        },
+    )
+}
 
-            eprintln!("articles, query = {:?}", &query);
+handlebars_helper!(hex_helper: |v: i64| format!("0x{:x}", v));
 
-            let mut index = state.index.lock().unwrap();
-            for article in &mut *index.search(&query).unwrap() {
-                let mut buffer = String::new();
-                article.body().unwrap().read_to_string(&mut buffer).unwrap();
-                out.write(dbg!(handlebars.render_template(&buffer, &()))?.as_ref())?;
-            }
+// Encodes a value to be part of a URL, such as /articles?q=tag:{{url_encode tag}}
+handlebars_helper!(url_encode_helper: |v: str| RawStr::new(v).percent_encode().to_string());
 
-            Ok(())
-        },
+// Writes an img offering an uploaded image at several widths, such as
+// {{ srcset "/media/<hash>/photo.jpg" widths="320 640" fmt="webp" alt="A photo" }}.
+// The image is given by its media URL, its hash or a media record. Widths
+// other than the defaults must be among the image_widths of the site.
+fn wrapped_srcset_helper(state: Weak<App>) -> Box<dyn HelperDef + Sync + Send> {
+    Box::new(
+        move |h: &Helper,
+              _: &Handlebars,
+              _: &Context,
+              _: &mut RenderContext,
+              out: &mut dyn Output|
+              -> HelperResult { srcset_helper(h, &*upgrade(&state)?, out) },
     )
 }
 
-handlebars_helper!(hex_helper: |v: i64| format!("0x{:x}", v));
+fn srcset_helper(h: &Helper, state: &App, out: &mut dyn Output) -> HelperResult {
+    let url = match h.param(0).map(|v| v.value()) {
+        Some(Value::String(url)) => {
+            let path = url.strip_prefix("/media/").unwrap_or(url);
+            let hash = path.split('/').next().unwrap_or_default();
+            is_hash(hash).then(|| format!("/media/{}", path))
+        }
+        Some(Value::Object(media)) => match (&media["hash"], &media["name"]) {
+            (Value::String(hash), Value::String(name)) if is_hash(hash) => {
+                Some(format!("/media/{}/{}", hash, name))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+    .ok_or_else(|| RenderError::new("requires a media URL, hash or record"))?;
+    let attribute = |name: &str| h.hash_get(name).and_then(|v| v.value().as_str());
+
+    let widths = match attribute("widths") {
+        Some(widths) => widths
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|width| !width.is_empty())
+            .map(|width| width.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RenderError::new("widths must be numbers"))?,
+        None => DEFAULT_WIDTHS.to_vec(),
+    };
+    if let Some(width) = widths
+        .iter()
+        .find(|width| !images::is_allowed_size(**width, &state.image_widths))
+    {
+        return Err(RenderError::new(format!(
+            "width {} is not one of the image_widths of the site",
+            width
+        )));
+    }
+    let largest = widths
+        .iter()
+        .max()
+        .ok_or_else(|| RenderError::new("requires at least one width"))?;
+    let format = match attribute("fmt") {
+        Some(name) => Some(
+            images::Format::parse(name)
+                .ok_or_else(|| RenderError::new("fmt must be jpeg, png, webp or gif"))?,
+        ),
+        None => None,
+    };
+
+    let src = images::variant_url(&url, *largest, format);
+    let srcset = images::srcset(&url, &widths, format);
+    out.write(&format!(
+        r#"<img src="{}" srcset="{}""#,
+        handlebars::html_escape(&src),
+        handlebars::html_escape(&srcset)
+    ))?;
+    for name in ["sizes", "alt", "class"] {
+        if let Some(value) = attribute(name) {
+            out.write(&format!(
+                r#" {}="{}""#,
+                name,
+                handlebars::html_escape(value)
+            ))?;
+        }
+    }
+    out.write(">")?;
+    Ok(())
+}
 
 fn flash_helper(
     _: &Helper,
@@ -90,57 +524,95 @@ fn flash_helper(
     Ok(())
 }
 
-fn admin_article_title_helper(
-    _: &Helper,
+// Writes the value of a field of the article form, escaped for an attribute
+fn admin_form_helper(
+    h: &Helper,
     _: &Handlebars,
     context: &Context,
     _: &mut RenderContext,
     out: &mut dyn Output,
 ) -> HelperResult {
-    if let Value::String(ref text) = context.data()["article"]["title"] {
+    let field = h
+        .param(0)
+        .and_then(|v| v.value().as_str())
+        .ok_or_else(|| RenderError::new("requires a field name"))?;
+    if let Value::String(ref text) = context.data()["form"][field] {
         out.write(&handlebars::html_escape(text))?;
     }
     Ok(())
 }
 
-fn admin_article_id_helper(
-    _: &Helper,
+// Lists the validation errors of a form field, including those of its sub
+// fields such as properties.price for properties
+fn field_errors_helper(
+    h: &Helper,
     _: &Handlebars,
     context: &Context,
     _: &mut RenderContext,
     out: &mut dyn Output,
 ) -> HelperResult {
-    if let Value::String(ref text) = context.data()["article"]["id"] {
-        out.write(&handlebars::html_escape(text))?;
-    }
-    Ok(())
-}
-
-fn admin_article_body_helper(
-    _: &Helper,
-    _: &Handlebars,
-    context: &Context,
-    _: &mut RenderContext,
-    out: &mut dyn Output,
-) -> HelperResult {
-    if let Value::String(ref text) = context.data()["article"]["body"] {
-        out.write(&handlebars::html_escape(text))?;
+    let field = h
+        .param(0)
+        .and_then(|v| v.value().as_str())
+        .ok_or_else(|| RenderError::new("requires a field name"))?;
+    if let Value::Object(ref fields) = context.data()["errors"]["fields"] {
+        let nested = format!("{}.", field);
+        for (name, messages) in fields {
+            if name != field && !name.starts_with(&nested) {
+                continue;
+            }
+            for message in messages.as_array().into_iter().flatten() {
+                out.write(r#"<p class="_field_error">"#)?;
+                if name != field {
+                    out.write(&handlebars::html_escape(&name[nested.len()..]))?;
+                    out.write(" ")?;
+                }
+                out.write(&handlebars::html_escape(
+                    message.as_str().unwrap_or_default(),
+                ))?;
+                out.write("</p>")?;
+            }
+        }
     }
     Ok(())
 }
 
-pub fn register_helpers(handlebars: &mut Handlebars, state: Arc<App>) {
+pub fn register_helpers(handlebars: &mut Handlebars, state: Weak<App>) {
     handlebars.set_strict_mode(true);
 
     // User helpers
     handlebars.register_helper("hex", Box::new(hex_helper));
+    handlebars.register_helper("url_encode", Box::new(url_encode_helper));
+    handlebars.register_helper("srcset", wrapped_srcset_helper(state.clone()));
+    handlebars.register_helper("block", Box::new(block_helper));
     handlebars.register_helper("article", wrapped_article_helper(state.clone()));
-    handlebars.register_helper("articles", wrapped_articles_helper(state));
+    handlebars.register_helper(
+        "articles",
+        Box::new(ArticlesHelper {
+            state: state.clone(),
+        }),
+    );
+    handlebars.register_helper(
+        "paginate",
+        Box::new(PaginateHelper {
+            state: state.clone(),
+        }),
+    );
+    handlebars.register_helper(
+        "body",
+        Box::new(BodyHelper {
+            state: state.clone(),
+        }),
+    );
+    handlebars.register_helper(
+        "children",
+        wrapped_linked_helper(state.clone(), LinkKind::Parent.name()),
+    );
+    handlebars.register_helper("backlinks", wrapped_linked_helper(state, LinkFilter::ANY));
 
     // Internal helpers
     handlebars.register_helper("_flash", Box::new(flash_helper));
 
-    handlebars.register_helper("_admin_article_title", Box::new(admin_article_title_helper));
-    handlebars.register_helper("_admin_article_id", Box::new(admin_article_id_helper));
-    handlebars.register_helper("_admin_article_body", Box::new(admin_article_body_helper));
+    handlebars.register_helper("_admin_form", Box::new(admin_form_helper));
+    handlebars.register_helper("_field_errors", Box::new(field_errors_helper));
 }
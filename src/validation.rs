@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde_derive::Serialize;
+
+// ValidationErrors collects problems with user supplied input per field, so
+// they can be shown next to the offending input or returned from the API
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ValidationErrors {
+    pub fields: BTreeMap<String, Vec<String>>,
+}
+
+impl ValidationErrors {
+    pub fn add<F: Into<String>, M: Into<String>>(&mut self, field: F, message: M) {
+        self.fields
+            .entry(field.into())
+            .or_default()
+            .push(message.into());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (field, messages) in self.fields.iter() {
+            for message in messages {
+                if !first {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: {}", field, message)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+#[cfg(test)]
+mod tests {
+    use crate::validation::*;
+
+    #[test]
+    fn test_validation_errors() {
+        let mut errors = ValidationErrors::default();
+        assert!(errors.clone().into_result().is_ok());
+
+        errors.add("title", "is required");
+        errors.add("properties.price", "must be a decimal");
+        errors.add("title", "is too long");
+        assert_eq!(errors.fields["title"].len(), 2);
+        assert_eq!(
+            errors.to_string(),
+            "properties.price: must be a decimal, title: is required, title: is too long"
+        );
+        assert!(errors.into_result().is_err());
+    }
+}